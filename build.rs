@@ -0,0 +1,32 @@
+//! Embeds the git revision the tool is built from, so each sync records which build produced it.
+
+use std::process::Command;
+
+fn main() {
+    // Set by the Nix package, which builds without the `.git` directory.
+    println!("cargo::rerun-if-env-changed=NIX_ZED_EXTENSIONS_REV");
+    println!("cargo::rerun-if-changed=.git/HEAD");
+    println!("cargo::rerun-if-changed=.git/refs");
+    println!("cargo::rerun-if-changed=.git/packed-refs");
+
+    let rev = std::env::var("NIX_ZED_EXTENSIONS_REV")
+        .ok()
+        .filter(|rev| !rev.is_empty())
+        .or_else(git_rev)
+        .unwrap_or_else(|| "unknown".to_owned());
+
+    println!("cargo::rustc-env=NIX_ZED_EXTENSIONS_REV={rev}");
+}
+
+fn git_rev() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_owned())
+}
@@ -51,7 +51,10 @@
 
       # nix build .#<name>
       packages = perSystemPkgs (pkgs: {
-        nix-zed-extensions = pkgs.nix-zed-extensions;
+        # Only the copy used for syncing records the revision, so the builders don't change with every commit.
+        nix-zed-extensions = pkgs.nix-zed-extensions.override {
+          rev = self.rev or self.dirtyRev or null;
+        };
 
         zed-grammars = pkgs.zed-grammars;
         zed-extensions = pkgs.zed-extensions;
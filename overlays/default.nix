@@ -1,4 +1,25 @@
-final: prev: {
+final: prev:
+
+let
+  # Sources shared between extensions and grammars, like `sources.<repository>-<hash>`.
+  sources = builtins.listToAttrs (
+    map (filename: {
+      name = prev.lib.removeSuffix ".json" filename;
+      value = prev.lib.importJSON (../generated/sources + "/${filename}");
+    }) (
+      builtins.filter (f: prev.lib.hasSuffix ".json" f) (
+        if builtins.pathExists ../generated/sources then
+          builtins.attrNames (builtins.readDir ../generated/sources)
+        else
+          [ ]
+      )
+    )
+  );
+
+  # Tombstones, version histories and older data embed their source instead of a key.
+  resolveSource = src: if builtins.isString src then sources.${src} else src;
+in
+{
   buildZedExtension = prev.callPackage ../pkgs/buildZedExtension { };
   buildZedRustExtension = prev.callPackage ../pkgs/buildZedRustExtension { };
   buildZedGrammar = prev.callPackage ../pkgs/buildZedGrammar { };
@@ -46,8 +67,14 @@ final: prev: {
     buildZedGrammar {
       inherit (grammar) name version grammarRoot;
 
+      # Missing from grammars synced before they were recorded.
+      hasParser = grammar.hasParser or null;
+      symbol = grammar.symbol or null;
+      scanner = grammar.scanner or null;
+      needsGenerate = grammar.needsGenerate or false;
+
       src = fetchgit {
-        inherit (grammar.src)
+        inherit (resolveSource grammar.src)
           url
           rev
           hash
@@ -59,19 +86,42 @@ final: prev: {
       };
     };
 
-  zed-grammars = builtins.listToAttrs (
-    map (
-      filename:
-      let
-        id = prev.lib.removeSuffix ".json" filename;
-        grammar = builtins.fromJSON (builtins.readFile (../generated/grammars + "/${filename}"));
-      in
-      {
-        name = id;
-        value = final.callPackage (final.mkZedGrammar grammar) { };
-      }
-    ) (builtins.filter (f: prev.lib.hasSuffix ".json" f) (builtins.attrNames (builtins.readDir ../generated/grammars)))
-  );
+  zed-grammars =
+    let
+      grammars = builtins.listToAttrs (
+        map (
+          filename:
+          let
+            id = prev.lib.removeSuffix ".json" filename;
+            grammar = builtins.fromJSON (builtins.readFile (../generated/grammars + "/${filename}"));
+          in
+          {
+            name = id;
+            value = final.callPackage (final.mkZedGrammar grammar) { };
+          }
+        ) (builtins.filter (f: prev.lib.hasSuffix ".json" f) (builtins.attrNames (builtins.readDir ../generated/grammars)))
+      );
+
+      # Per-extension names, like `<extension_id>__<grammar_id>`.
+      aliases = builtins.mapAttrs (alias: id: grammars.${id}) (
+        if builtins.pathExists ../generated/grammar-aliases.json then
+          prev.lib.importJSON ../generated/grammar-aliases.json
+        else
+          { }
+      );
+
+      # Grammars used to be named `<extension_id>_<grammar_id>`, only kept where that's unambiguous.
+      legacy = prev.lib.concatMapAttrs (
+        old: names:
+        let
+          alias = builtins.head names;
+        in
+        prev.lib.optionalAttrs (builtins.length names == 1) {
+          ${old} = prev.lib.warn "zed-grammars.${old} has been renamed to zed-grammars.${alias}" aliases.${alias};
+        }
+      ) (prev.lib.groupBy (builtins.replaceStrings [ "__" ] [ "_" ]) (builtins.attrNames aliases));
+    in
+    legacy // aliases // grammars;
 
   mkZedExtension =
     extension:
@@ -89,7 +139,7 @@ final: prev: {
         inherit (extension) name version extensionRoot;
 
         src = fetchgit {
-          inherit (extension.src)
+          inherit (resolveSource extension.src)
             url
             rev
             hash
@@ -98,6 +148,9 @@ final: prev: {
             deepClone
             leaveDotGit
             ;
+
+          # Missing from sources synced before it was recorded.
+          sparseCheckout = (resolveSource extension.src).sparseCheckout or [ ];
         };
 
         grammars = map (id: zed-grammars."${id}") extension.grammars;
@@ -123,18 +176,97 @@ final: prev: {
       }
     );
 
-  zed-extensions = builtins.listToAttrs (
+  # Extensions removed from the registry, still built from their last known version.
+  zed-extension-tombstones = builtins.listToAttrs (
+    map (
+      filename:
+      let
+        tombstone = builtins.fromJSON (builtins.readFile (../generated/tombstones + "/${filename}"));
+      in
+      {
+        inherit (tombstone) name;
+        value =
+          prev.lib.warn
+            "zed-extensions.${tombstone.name} was removed from its registry on ${tombstone.removed}, using its last known version ${tombstone.extension.version} from ${tombstone.extension.src.url}"
+            (
+              final.callPackage (final.mkZedExtension tombstone.extension) {
+                zed-grammars = builtins.listToAttrs (
+                  map (grammar: {
+                    name = grammar.id;
+                    value = final.callPackage (final.mkZedGrammar grammar) { };
+                  }) tombstone.grammars
+                );
+              }
+            );
+      }
+    ) (
+      builtins.filter (f: prev.lib.hasSuffix ".json" f) (
+        if builtins.pathExists ../generated/tombstones then
+          builtins.attrNames (builtins.readDir ../generated/tombstones)
+        else
+          [ ]
+      )
+    )
+  );
+
+  # Old names of renamed extensions.
+  zed-extension-aliases = prev.lib.mapAttrs (
+    old: new:
+    prev.lib.warn "zed-extensions.${old} has been renamed to zed-extensions.${new}" final.zed-extensions.${new}
+  ) (if builtins.pathExists ../generated/aliases.json then prev.lib.importJSON ../generated/aliases.json else { });
+
+  zed-extensions =
+    final.zed-extension-tombstones
+    // final.zed-extension-aliases
+    // builtins.listToAttrs (
+      map (
+        filename:
+        let
+          extension = builtins.fromJSON (builtins.readFile (../generated/extensions + "/${filename}"));
+        in
+        {
+          inherit (extension) name;
+          value = final.callPackage (final.mkZedExtension extension) {
+            inherit (final) zed-grammars;
+          };
+        }
+      ) (builtins.filter (f: prev.lib.hasSuffix ".json" f) (builtins.attrNames (builtins.readDir ../generated/extensions)))
+    );
+
+  # Kept versions of each extension, like `zed-extension-versions.<name>."<version>"`.
+  zed-extension-versions = builtins.listToAttrs (
     map (
       filename:
       let
-        extension = builtins.fromJSON (builtins.readFile (../generated/extensions + "/${filename}"));
+        history = builtins.fromJSON (builtins.readFile (../generated/versions + "/${filename}"));
+
+        versions = builtins.listToAttrs (
+          map (entry: {
+            name = entry.extension.version;
+            value = final.callPackage (final.mkZedExtension entry.extension) {
+              zed-grammars = builtins.listToAttrs (
+                map (grammar: {
+                  name = grammar.id;
+                  value = final.callPackage (final.mkZedGrammar grammar) { };
+                }) entry.grammars
+              );
+            };
+          }) history.versions
+        );
       in
       {
-        inherit (extension) name;
-        value = final.callPackage (final.mkZedExtension extension) {
-          inherit (final) zed-grammars;
+        name = prev.lib.removeSuffix ".json" filename;
+        value = versions // {
+          latest = versions.${history.latest};
         };
       }
-    ) (builtins.filter (f: prev.lib.hasSuffix ".json" f) (builtins.attrNames (builtins.readDir ../generated/extensions)))
+    ) (
+      builtins.filter (f: prev.lib.hasSuffix ".json" f) (
+        if builtins.pathExists ../generated/versions then
+          builtins.attrNames (builtins.readDir ../generated/versions)
+        else
+          [ ]
+      )
+    )
   );
 }
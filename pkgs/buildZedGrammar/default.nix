@@ -2,6 +2,8 @@
   lib,
   stdenvNoCC,
   wasi-sdk,
+  tree-sitter,
+  nodejs,
   ...
 }:
 
@@ -13,6 +15,10 @@ lib.extendMkDerivation {
     "src"
     "version"
     "grammarRoot"
+    "hasParser"
+    "symbol"
+    "scanner"
+    "needsGenerate"
   ];
 
   extendDrvArgs =
@@ -23,11 +29,27 @@ lib.extendMkDerivation {
       src,
       version,
       grammarRoot ? null,
+      # Whether `src/parser.c` exists, `null` if unknown.
+      hasParser ? null,
+      # The `tree_sitter_*` function defined in `src/parser.c`, if it doesn't match `name`.
+      symbol ? null,
+      # The external scanner, like `{ file = "src/scanner.cc"; language = "cpp"; }`.
+      # Defaults to `src/scanner.c` if it exists.
+      scanner ? null,
+      # Whether to run `tree-sitter generate` first, for grammars that don't commit `src/parser.c`.
+      needsGenerate ? false,
       ...
     }:
 
     let
       grammarDir = if grammarRoot == null then "." else grammarRoot;
+
+      # Zed looks up the language function by grammar name.
+      export = "tree_sitter_${name}";
+      rename = lib.optionalString (symbol != null && symbol != export) "-D${symbol}=${export}";
+
+      # Uses the tree-sitter CLI from nixpkgs, whatever version the grammar declares.
+      generate = lib.optionalString needsGenerate "tree-sitter generate";
     in
     {
       pname = "zed-grammar-${name}";
@@ -35,30 +57,71 @@ lib.extendMkDerivation {
 
       nativeBuildInputs = [
         wasi-sdk
+      ]
+      ++ lib.optionals needsGenerate [
+        tree-sitter
+        nodejs
       ];
 
-      buildPhase = ''
-        mkdir -p $out/share/zed/grammars
+      buildPhase =
+        if scanner != null && scanner.language == "cpp" then
+          ''
+            mkdir -p $out/share/zed/grammars
+
+            pushd ${grammarDir}
+
+            ${generate}
+
+            ${wasi-sdk}/bin/clang -fPIC -Os ${rename} -I src -c src/parser.c -o parser.o
+            ${wasi-sdk}/bin/clang++ -fPIC -Os -fno-exceptions -I src -c ${scanner.file} -o scanner.o
 
-        pushd ${grammarDir}
+            ${wasi-sdk}/bin/clang++ \
+              -fPIC \
+              -shared \
+              -Wl,--export=${export} \
+              -o $out/share/zed/grammars/${name}.wasm \
+              parser.o scanner.o
 
-        SRC="src/parser.c"
-        if [ -f src/scanner.c ]; then
-          SRC="$SRC src/scanner.c"
-        fi
+            popd
+          ''
+        else
+          ''
+            mkdir -p $out/share/zed/grammars
 
-        ${wasi-sdk}/bin/clang \
-          -fPIC \
-          -shared \
-          -Os \
-          -Wl,--export=tree_sitter_${name} \
-          -o $out/share/zed/grammars/${name}.wasm \
-          -I src \
-          $SRC
+            pushd ${grammarDir}
 
-        popd
-      '';
+            ${generate}
+
+            SRC="src/parser.c"
+            ${
+              if scanner != null then
+                ''SRC="$SRC ${scanner.file}"''
+              else
+                ''
+                  if [ -f src/scanner.c ]; then
+                    SRC="$SRC src/scanner.c"
+                  fi
+                ''
+            }
+
+            ${wasi-sdk}/bin/clang \
+              -fPIC \
+              -shared \
+              -Os \
+              ${rename} \
+              -Wl,--export=${export} \
+              -o $out/share/zed/grammars/${name}.wasm \
+              -I src \
+              $SRC
+
+            popd
+          '';
 
       dontInstall = true;
+
+      meta = {
+        # Grammars without a parser or a definition to generate it from can't be built.
+        broken = hasParser == false && !needsGenerate;
+      };
     };
 }
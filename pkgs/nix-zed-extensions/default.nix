@@ -4,6 +4,8 @@
   makeWrapper,
   fetch-cargo-vendor-util,
   nix-prefetch-git,
+  # Git revision recorded in `generated/sync.json`.
+  rev ? null,
 }:
 
 rustPlatform.buildRustPackage {
@@ -33,6 +35,10 @@ rustPlatform.buildRustPackage {
     lockFile = ../../Cargo.lock;
   };
 
+  env = lib.optionalAttrs (rev != null) {
+    NIX_ZED_EXTENSIONS_REV = rev;
+  };
+
   doCheck = false;
 
   postInstall = ''
@@ -0,0 +1,119 @@
+//! Old names of extensions that were renamed in their registry.
+
+use std::collections::BTreeMap;
+
+use crate::output::{Extension, ExtensionKind};
+
+/// Maps extensions removed in a sync to the extensions added in the same sync that likely replaced them under a new name.
+///
+/// A rename keeps the same repository, path and manifest `id`.
+/// The `id` is only compared when both extensions have one, as older data doesn't record it.
+/// Extensions that existed side by side are never compared, as they can't be renames of each other.
+#[must_use]
+pub fn detect_renames(removed: &[&Extension], added: &[&Extension]) -> BTreeMap<String, String> {
+    let mut aliases = BTreeMap::new();
+    for old in removed {
+        let candidates: Vec<&Extension> = added
+            .iter()
+            .copied()
+            .filter(|new| {
+                new.name != old.name
+                    && new.src.url == old.src.url
+                    && location(new) == location(old)
+                    && (new.id.is_none() || old.id.is_none() || new.id == old.id)
+            })
+            .collect();
+
+        match candidates.as_slice() {
+            [] => (),
+            [new] => {
+                aliases.insert(old.name.clone(), new.name.clone());
+            }
+            _ => tracing::warn!(
+                name = old.name,
+                candidates = ?candidates.iter().map(|new| &new.name).collect::<Vec<_>>(),
+                "Ambiguous rename, not adding an alias"
+            ),
+        }
+    }
+
+    aliases
+}
+
+/// Path of the extension in its repository, as standalone crates keep it in their Cargo root instead of the extension root.
+fn location(extension: &Extension) -> Option<String> {
+    let cargo_root = match &extension.kind {
+        ExtensionKind::Rust { cargo_root, .. } => cargo_root.as_deref(),
+        ExtensionKind::Plain => None,
+    };
+
+    match (cargo_root, extension.extension_root.as_deref()) {
+        (Some(cargo_root), Some(extension_root)) => Some(format!("{cargo_root}/{extension_root}")),
+        (cargo_root, extension_root) => cargo_root.or(extension_root).map(ToOwned::to_owned),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::Source;
+
+    fn extension(name: &str, cargo_root: Option<&str>, extension_root: Option<&str>) -> Extension {
+        Extension {
+            name: name.to_owned(),
+            id: None,
+            version: "0.1.0".to_owned(),
+            rev: None,
+            path: None,
+            src: Source {
+                url: "https://github.com/example/monorepo".to_owned(),
+                rev: "0123456789abcdef".to_owned(),
+                date: String::new(),
+                path: String::new(),
+                sha256: String::new(),
+                hash: String::new(),
+                fetch_lfs: false,
+                fetch_submodules: false,
+                deep_clone: false,
+                leave_dot_git: false,
+                sparse_checkout: vec![],
+            },
+            extension_root: extension_root.map(ToOwned::to_owned),
+            grammars: vec![],
+            languages: None,
+            registry: None,
+            registry_rev: None,
+            kind: ExtensionKind::Rust {
+                cargo_root: cargo_root.map(ToOwned::to_owned),
+                cargo_hash: String::new(),
+                cargo_lock: None,
+            },
+        }
+    }
+
+    #[test]
+    fn detects_renames_at_the_same_path() {
+        let old = extension("old", Some("one"), None);
+        let new = extension("new", Some("one"), None);
+
+        let renames = detect_renames(&[&old], &[&new]);
+        assert_eq!(renames.get("old").map(String::as_str), Some("new"));
+    }
+
+    #[test]
+    fn compares_standalone_crates_by_cargo_root() {
+        let old = extension("old", Some("one"), None);
+        let new = extension("new", Some("two"), None);
+
+        assert!(detect_renames(&[&old], &[&new]).is_empty());
+    }
+
+    #[test]
+    fn skips_ambiguous_renames() {
+        let old = extension("old", None, Some("one"));
+        let first = extension("first", None, Some("one"));
+        let second = extension("second", Some("one"), None);
+
+        assert!(detect_renames(&[&old], &[&first, &second]).is_empty());
+    }
+}
@@ -0,0 +1,235 @@
+//! Reconstructs older versions of an extension from the git history of its registry.
+
+use std::{collections::BTreeMap, path::Path};
+
+use smol::{fs, process::Command};
+use tracing::Instrument;
+
+use crate::{
+    generated::GeneratedDir,
+    history::{KeepVersions, VersionHistory},
+    registry::{
+        CUSTOM_REGISTRY, OFFICIAL_REGISTRY, RegistryClone, RegistryEntry, RegistryExtension,
+        RegistrySource, clone_registry,
+    },
+    source::SourceCache,
+    sync::{process_extension, scratch_dir},
+    validate::validate_registry_extension,
+};
+
+/// Adds every version of `name` listed in the history of its registry to its version history.
+///
+/// Versions are processed one at a time, newest first, and existing versions are left alone.
+pub async fn backfill_extension(
+    name: &str,
+    sources: &[(String, RegistrySource)],
+    generated: &GeneratedDir,
+) -> anyhow::Result<()> {
+    let output = generated.load().await?;
+    let Some(current) = output
+        .extensions
+        .iter()
+        .find(|extension| extension.name == name)
+    else {
+        anyhow::bail!("Unknown extension: '{name}'");
+    };
+
+    let registry = current.registry.as_deref().unwrap_or(OFFICIAL_REGISTRY);
+    if registry == CUSTOM_REGISTRY {
+        anyhow::bail!("Custom extension '{name}' has no registry history");
+    }
+
+    let Some((_, source)) = sources.iter().find(|(source, _)| source == registry) else {
+        anyhow::bail!("Unknown registry '{registry}' for extension '{name}'");
+    };
+
+    // Every revision of `extensions.toml` is read, so fetch them all at once.
+    // Cloned once per run, as it's shared by every extension from the same registry.
+    let dir = scratch_dir().join("registries").join(registry);
+    if !dir.exists() {
+        clone_registry(registry, source, &dir, RegistryClone::Full).await?;
+    }
+
+    let candidates = registry_history(name, registry, &dir).await?;
+    tracing::info!(
+        name = name,
+        versions = candidates.len(),
+        "Found versions in registry history"
+    );
+
+    // Make sure the current version heads the history.
+    let grammars: Vec<_> = output
+        .grammars
+        .iter()
+        .filter(|grammar| current.grammars.contains(&grammar.id))
+        .cloned()
+        .collect();
+
+    generated
+        .record_version(current, &grammars, Some(KeepVersions::All))
+        .await?;
+
+    let Some(history) = VersionHistory::load(&generated.history(name)).await? else {
+        anyhow::bail!("Missing version history for '{name}'");
+    };
+
+    // Processed separately, so the current lockfile isn't overwritten.
+    let scratch = GeneratedDir::new(scratch_dir().join("backfill"));
+    scratch.create().await?;
+
+    let cache = SourceCache::default();
+
+    for candidate in candidates {
+        if history
+            .versions
+            .iter()
+            .any(|existing| existing.extension.version == candidate.version)
+        {
+            tracing::debug!(
+                name = name,
+                version = candidate.version,
+                "Version already kept"
+            );
+            continue;
+        }
+
+        // Older registry entries never went through the checks of a regular sync.
+        if let Err(err) = validate_registry_extension(&candidate) {
+            tracing::error!(
+                version = candidate.version,
+                err = ?err,
+                "Rejected registry entry"
+            );
+            continue;
+        }
+
+        let span = tracing::info_span!(
+            "process_extension",
+            name = %candidate.name,
+            version = %candidate.version,
+        );
+
+        match process_extension(candidate, &scratch, &cache)
+            .instrument(span)
+            .await
+        {
+            Ok(Some((extension, grammars))) => {
+                if generated
+                    .backfill_version(&extension, &grammars, &scratch)
+                    .await?
+                {
+                    tracing::info!(name = name, version = extension.version, "Added version");
+                }
+            }
+            Ok(None) => (),
+            Err(err) => tracing::error!(
+                err = ?err,
+                "Error processing extension"
+            ),
+        }
+    }
+
+    fs::remove_dir_all(scratch.root()).await?;
+
+    Ok(())
+}
+
+/// Lists each distinct version of `name` in the registry history, newest first.
+///
+/// Each version is resolved at the last registry commit that listed it.
+async fn registry_history(
+    name: &str,
+    registry: &str,
+    dir: &Path,
+) -> anyhow::Result<Vec<RegistryExtension>> {
+    let Some(commits) = git(
+        dir,
+        &[
+            "log",
+            "--first-parent",
+            "--format=%H",
+            "--",
+            "extensions.toml",
+        ],
+    )
+    .await?
+    else {
+        anyhow::bail!("Failed to read registry history");
+    };
+
+    let mut extensions: Vec<RegistryExtension> = vec![];
+    for commit in commits.lines() {
+        let Some(registry_toml) = git(dir, &["show", &format!("{commit}:extensions.toml")]).await?
+        else {
+            continue;
+        };
+
+        let Ok(entries) = toml::from_str::<BTreeMap<String, RegistryEntry>>(&registry_toml) else {
+            tracing::warn!(commit = commit, "Failed to parse registry at commit");
+            continue;
+        };
+
+        let Some(entry) = entries.get(name) else {
+            continue;
+        };
+
+        if extensions
+            .iter()
+            .any(|extension| extension.version == entry.version)
+        {
+            continue;
+        }
+
+        // Resolve the submodule gitlink, like `160000 commit <rev>\t<path>`
+        let Some(rev) = git(dir, &["ls-tree", commit, "--", &entry.submodule])
+            .await?
+            .and_then(|tree| tree.split_whitespace().nth(2).map(str::to_owned))
+        else {
+            tracing::warn!(
+                commit = commit,
+                version = entry.version,
+                "Missing submodule revision"
+            );
+            continue;
+        };
+
+        let url = format!("submodule.{}.url", entry.submodule);
+        let blob = format!("{commit}:.gitmodules");
+        let Some(repository) = git(dir, &["config", "--blob", &blob, "--get", &url]).await? else {
+            tracing::warn!(
+                commit = commit,
+                version = entry.version,
+                "Missing submodule repository"
+            );
+            continue;
+        };
+
+        extensions.push(RegistryExtension {
+            name: name.to_owned(),
+            version: entry.version.clone(),
+            repository: repository.trim_end_matches(".git").to_owned(),
+            path: entry.path.clone(),
+            rev,
+            registry: registry.to_owned(),
+            registry_rev: Some(commit.to_owned()),
+        });
+    }
+
+    Ok(extensions)
+}
+
+/// Runs git in `dir`, returning its trimmed output, or `None` if it failed.
+async fn git(dir: &Path, args: &[&str]) -> anyhow::Result<Option<String>> {
+    let output = Command::new("git")
+        .kill_on_drop(true)
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    Ok(Some(String::from_utf8(output.stdout)?.trim().to_owned()))
+}
@@ -0,0 +1,67 @@
+//! Extensions from outside the official registry.
+
+use std::{collections::BTreeMap, path::Path};
+
+use serde::Deserialize;
+use smol::fs;
+
+use crate::{
+    overrides::validate_source,
+    registry::{CUSTOM_REGISTRY, RegistryExtension},
+};
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CustomEntry {
+    pub repository: String,
+    pub rev: String,
+    #[serde(default)]
+    pub path: Option<String>,
+}
+
+/// Reads and validates the custom extensions file, treating a missing file as empty.
+pub async fn load_custom_extensions(path: &Path) -> anyhow::Result<Vec<RegistryExtension>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let content = fs::read_to_string(path).await?;
+    let entries: BTreeMap<String, CustomEntry> = toml::from_str(&content)?;
+
+    let mut problems = vec![];
+    for (name, entry) in &entries {
+        problems.extend(
+            validate_source(
+                Some(&entry.rev),
+                Some(&entry.repository),
+                entry.path.as_deref(),
+            )
+            .into_iter()
+            .map(|problem| format!("{name}: {problem}")),
+        );
+    }
+
+    if !problems.is_empty() {
+        for problem in &problems {
+            tracing::error!("{problem}");
+        }
+
+        anyhow::bail!("Invalid custom extensions in {}", path.display());
+    }
+
+    Ok(entries
+        .into_iter()
+        .map(|(name, entry)| RegistryExtension {
+            name,
+            // The real version is only known once the manifest has been read.
+            version: entry.rev.clone(),
+            repository: entry.repository,
+            path: entry
+                .path
+                .map(|path| path.trim_start_matches("./").to_owned()),
+            rev: entry.rev,
+            registry: CUSTOM_REGISTRY.to_owned(),
+            registry_rev: None,
+        })
+        .collect())
+}
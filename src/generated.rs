@@ -0,0 +1,774 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use smol::{fs, io::AsyncWriteExt, stream::StreamExt};
+
+use crate::{
+    history::{HistoricalVersion, KeepVersions, VersionHistory},
+    metadata::SyncMetadata,
+    output::{Extension, ExtensionKind, Grammar, NixExtensions, Source},
+    tombstone::Tombstone,
+};
+
+/// A directory of generated data, laid out like `generated/`.
+#[derive(Debug, Clone)]
+pub struct GeneratedDir {
+    root: PathBuf,
+}
+
+impl GeneratedDir {
+    #[must_use]
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    #[must_use]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    #[must_use]
+    pub fn extensions_dir(&self) -> PathBuf {
+        self.root.join("extensions")
+    }
+
+    #[must_use]
+    pub fn grammars_dir(&self) -> PathBuf {
+        self.root.join("grammars")
+    }
+
+    #[must_use]
+    pub fn sources_dir(&self) -> PathBuf {
+        self.root.join("sources")
+    }
+
+    #[must_use]
+    pub fn source(&self, key: &str) -> PathBuf {
+        self.sources_dir().join(format!("{key}.json"))
+    }
+
+    #[must_use]
+    pub fn lockfile(&self, name: &str) -> PathBuf {
+        self.extensions_dir().join(format!("{name}.lock"))
+    }
+
+    #[must_use]
+    pub fn versions_dir(&self) -> PathBuf {
+        self.root.join("versions")
+    }
+
+    #[must_use]
+    pub fn history(&self, name: &str) -> PathBuf {
+        self.versions_dir().join(format!("{name}.json"))
+    }
+
+    #[must_use]
+    pub fn history_lockfile(&self, name: &str, version: &str) -> PathBuf {
+        self.versions_dir()
+            .join(name)
+            .join(format!("{version}.lock"))
+    }
+
+    #[must_use]
+    pub fn tombstones_dir(&self) -> PathBuf {
+        self.root.join("tombstones")
+    }
+
+    #[must_use]
+    pub fn tombstone(&self, name: &str) -> PathBuf {
+        self.tombstones_dir().join(format!("{name}.json"))
+    }
+
+    #[must_use]
+    pub fn tombstone_lockfile(&self, name: &str) -> PathBuf {
+        self.tombstones_dir().join(format!("{name}.lock"))
+    }
+
+    #[must_use]
+    pub fn aliases(&self) -> PathBuf {
+        self.root.join("aliases.json")
+    }
+
+    #[must_use]
+    pub fn grammar_aliases(&self) -> PathBuf {
+        self.root.join("grammar-aliases.json")
+    }
+
+    #[must_use]
+    pub fn report(&self) -> PathBuf {
+        self.root.join("report.json")
+    }
+
+    #[must_use]
+    pub fn metadata(&self) -> PathBuf {
+        self.root.join("sync.json")
+    }
+
+    #[must_use]
+    pub fn shard(&self) -> PathBuf {
+        self.root.join("shard.json")
+    }
+
+    /// Loads every extension and grammar in the directory, skipping files that fail to parse.
+    pub async fn load(&self) -> anyhow::Result<NixExtensions> {
+        let mut output = NixExtensions::default();
+        let sources = self.load_sources().await?;
+
+        // Load existing extensions
+        let extensions_dir = self.extensions_dir();
+        if extensions_dir.exists() {
+            tracing::info!(dir = ?extensions_dir, "Loading existing extensions");
+
+            let mut entries = fs::read_dir(&extensions_dir).await?;
+            while let Some(entry) = entries.try_next().await? {
+                let path = entry.path();
+                if path
+                    .extension()
+                    .is_some_and(|extension| extension == "json")
+                {
+                    let content = fs::read_to_string(&path).await?;
+                    if let Some(extension) = resolve_source(&path, &content, &sources) {
+                        output.extensions.push(extension);
+                    }
+                }
+            }
+        }
+
+        // Load existing grammars
+        let grammars_dir = self.grammars_dir();
+        if grammars_dir.exists() {
+            tracing::info!(dir = ?grammars_dir, "Loading existing grammars");
+
+            let mut entries = fs::read_dir(&grammars_dir).await?;
+            while let Some(entry) = entries.try_next().await? {
+                let path = entry.path();
+                if path
+                    .extension()
+                    .is_some_and(|extension| extension == "json")
+                {
+                    let content = fs::read_to_string(&path).await?;
+                    if let Some(grammar) = resolve_source(&path, &content, &sources) {
+                        output.grammars.push(grammar);
+                    }
+                }
+            }
+        }
+
+        output.migrate_grammar_ids();
+
+        Ok(output)
+    }
+
+    /// Loads the shared source table, keyed by [`Source::key`].
+    pub async fn load_sources(&self) -> anyhow::Result<BTreeMap<String, Value>> {
+        let mut sources = BTreeMap::new();
+
+        let sources_dir = self.sources_dir();
+        if !sources_dir.exists() {
+            return Ok(sources);
+        }
+
+        let mut entries = fs::read_dir(&sources_dir).await?;
+        while let Some(entry) = entries.try_next().await? {
+            let path = entry.path();
+            let Some(key) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".json"))
+            else {
+                continue;
+            };
+
+            let content = fs::read_to_string(&path).await?;
+            if let Ok(source) = serde_json::from_str(&content) {
+                sources.insert(key.to_owned(), source);
+            }
+        }
+
+        Ok(sources)
+    }
+
+    pub async fn create(&self) -> anyhow::Result<()> {
+        fs::create_dir_all(self.extensions_dir()).await?;
+        fs::create_dir_all(self.grammars_dir()).await?;
+        fs::create_dir_all(self.sources_dir()).await?;
+        Ok(())
+    }
+
+    /// Writes `src` to the shared source table, unless the source stored under its key already covers it.
+    async fn write_source(&self, src: &Source) -> anyhow::Result<()> {
+        let path = self.source(&src.key());
+        if path.exists() {
+            let content = fs::read_to_string(&path).await?;
+            if let Ok(existing) = serde_json::from_str::<Source>(&content)
+                && existing != *src
+                && existing.covers(&src.sparse_checkout)
+            {
+                return Ok(());
+            }
+        }
+
+        write_json(&path, src).await?;
+        Ok(())
+    }
+
+    /// Writes a processed extension and its grammars, removing grammar files it no longer uses.
+    ///
+    /// Called as soon as an extension finishes, so an interrupted sync keeps its progress.
+    pub async fn checkpoint_extension(
+        &self,
+        extension: &Extension,
+        grammars: &[Grammar],
+        outdated: &[String],
+    ) -> anyhow::Result<()> {
+        // Sources go first, so the files referring to them are never left dangling.
+        self.write_source(&extension.src).await?;
+        for grammar in grammars {
+            self.write_source(&grammar.src).await?;
+        }
+
+        let grammars_dir = self.grammars_dir();
+        for grammar in grammars {
+            let path = grammars_dir.join(format!("{}.json", grammar.id));
+            write_json(&path, &with_source_key(grammar, &grammar.src)?).await?;
+        }
+
+        let path = self
+            .extensions_dir()
+            .join(format!("{}.json", extension.name));
+
+        write_json(&path, &with_source_key(extension, &extension.src)?).await?;
+
+        for id in outdated {
+            if extension.grammars.contains(id) {
+                continue;
+            }
+
+            let path = grammars_dir.join(format!("{id}.json"));
+            if path.exists() {
+                tracing::info!(id = id, "Removing outdated grammar file");
+                fs::remove_file(path).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the full output, removing any files that are no longer part of it.
+    pub async fn write(&self, output: &NixExtensions) -> anyhow::Result<()> {
+        self.create().await?;
+
+        // Remove temporary files of writes that were interrupted, which the sweeps below don't recognize
+        for dir in [
+            self.root().to_path_buf(),
+            self.extensions_dir(),
+            self.grammars_dir(),
+            self.sources_dir(),
+            self.tombstones_dir(),
+            self.versions_dir(),
+        ] {
+            remove_temp_files(&dir).await?;
+        }
+
+        // Write source files
+        let sources = output.sources();
+        for (key, src) in &sources {
+            write_json(&self.source(key), src).await?;
+        }
+
+        // Write extension files
+        let extensions_dir = self.extensions_dir();
+        let mut existing = fs::read_dir(&extensions_dir).await?;
+        while let Some(entry) = existing.try_next().await? {
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+
+            if let Some(name) = file_name.strip_suffix(".json")
+                && !output
+                    .extensions
+                    .iter()
+                    .any(|extension| extension.name == name)
+            {
+                tracing::info!(name = name, "Removing stale extension file");
+                fs::remove_file(entry.path()).await?;
+            }
+
+            if let Some(name) = file_name.strip_suffix(".lock")
+                && !output
+                    .extensions
+                    .iter()
+                    .any(|extension| extension.name == name)
+            {
+                tracing::info!(name = name, "Removing stale lockfile");
+                fs::remove_file(entry.path()).await?;
+            }
+        }
+
+        for extension in &output.extensions {
+            let name = &extension.name;
+            let path = extensions_dir.join(format!("{name}.json"));
+            write_json(&path, &with_source_key(extension, &extension.src)?).await?;
+        }
+
+        // Write grammar files
+        let grammars_dir = self.grammars_dir();
+        let mut existing = fs::read_dir(&grammars_dir).await?;
+        while let Some(entry) = existing.try_next().await? {
+            let id = entry
+                .file_name()
+                .to_string_lossy()
+                .trim_end_matches(".json")
+                .to_owned();
+
+            if !output.grammars.iter().any(|grammar| grammar.id == id) {
+                tracing::info!(id = id, "Removing stale grammar file");
+                fs::remove_file(entry.path()).await?;
+            }
+        }
+
+        for grammar in &output.grammars {
+            let id = &grammar.id;
+            let path = grammars_dir.join(format!("{id}.json"));
+            write_json(&path, &with_source_key(grammar, &grammar.src)?).await?;
+        }
+
+        write_json(&self.grammar_aliases(), &output.grammar_aliases()).await?;
+
+        // Remove sources nothing refers to anymore
+        let mut existing = fs::read_dir(self.sources_dir()).await?;
+        while let Some(entry) = existing.try_next().await? {
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            if let Some(key) = file_name.strip_suffix(".json")
+                && !sources.contains_key(key)
+            {
+                tracing::info!(key = key, "Removing stale source file");
+                fs::remove_file(entry.path()).await?;
+            }
+        }
+
+        // Remove tombstones of extensions that came back
+        let tombstones_dir = self.tombstones_dir();
+        if tombstones_dir.exists() {
+            let mut existing = fs::read_dir(&tombstones_dir).await?;
+            while let Some(entry) = existing.try_next().await? {
+                let file_name = entry.file_name();
+                let file_name = file_name.to_string_lossy();
+                let name = file_name
+                    .strip_suffix(".json")
+                    .or_else(|| file_name.strip_suffix(".lock"))
+                    .unwrap_or(&file_name);
+
+                if output
+                    .extensions
+                    .iter()
+                    .any(|extension| extension.name == name)
+                {
+                    tracing::info!(name = name, "Removing tombstone of restored extension");
+                    fs::remove_file(entry.path()).await?;
+                }
+            }
+        }
+
+        // Remove history of extensions that are no longer part of the output, unless kept with their tombstone
+        let tombstones = self.tombstones().await?;
+        let versions_dir = self.versions_dir();
+        if versions_dir.exists() {
+            let mut existing = fs::read_dir(&versions_dir).await?;
+            while let Some(entry) = existing.try_next().await? {
+                let file_name = entry.file_name();
+                let file_name = file_name.to_string_lossy();
+                let name = file_name.strip_suffix(".json").unwrap_or(&file_name);
+
+                if !output
+                    .extensions
+                    .iter()
+                    .any(|extension| extension.name == name)
+                    && !tombstones.iter().any(|tombstone| tombstone == name)
+                {
+                    tracing::info!(name = name, "Removing stale version history");
+
+                    if entry.file_type().await?.is_dir() {
+                        fs::remove_dir_all(entry.path()).await?;
+                    } else {
+                        fs::remove_file(entry.path()).await?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds a synced extension to its version history, dropping versions beyond `keep`.
+    ///
+    /// Extensions without a history are only recorded when `keep` is set.
+    pub async fn record_version(
+        &self,
+        extension: &Extension,
+        grammars: &[Grammar],
+        keep: Option<KeepVersions>,
+    ) -> anyhow::Result<()> {
+        let name = &extension.name;
+        let version = &extension.version;
+
+        let path = self.history(name);
+        let history = VersionHistory::load(&path).await?;
+        if history.is_none() && keep.is_none() {
+            return Ok(());
+        }
+
+        let mut extension = extension.clone();
+        self.store_history_lockfile(&mut extension, &self.lockfile(name))
+            .await?;
+
+        let mut history = history.unwrap_or_else(|| VersionHistory {
+            latest: version.clone(),
+            versions: vec![],
+        });
+
+        let dropped = history.push(
+            HistoricalVersion {
+                extension,
+                grammars: grammars.to_vec(),
+            },
+            keep,
+        );
+
+        for old in dropped {
+            tracing::info!(
+                name = name,
+                version = old.extension.version,
+                "Dropping old version"
+            );
+
+            let lockfile = self.history_lockfile(name, &old.extension.version);
+            if lockfile.exists() {
+                fs::remove_file(lockfile).await?;
+            }
+        }
+
+        fs::create_dir_all(self.versions_dir()).await?;
+        write_json(&path, &history).await?;
+
+        Ok(())
+    }
+
+    /// Adds an older version to the end of an existing version history, unless it's already there.
+    ///
+    /// Its lockfile is taken from `from`, where the version was processed.
+    /// Returns whether the version was added.
+    pub async fn backfill_version(
+        &self,
+        extension: &Extension,
+        grammars: &[Grammar],
+        from: &Self,
+    ) -> anyhow::Result<bool> {
+        let name = &extension.name;
+
+        let path = self.history(name);
+        let Some(mut history) = VersionHistory::load(&path).await? else {
+            anyhow::bail!("Missing version history for '{name}'");
+        };
+
+        if history
+            .versions
+            .iter()
+            .any(|existing| existing.extension.version == extension.version)
+        {
+            return Ok(false);
+        }
+
+        let mut extension = extension.clone();
+        self.store_history_lockfile(&mut extension, &from.lockfile(name))
+            .await?;
+
+        history.versions.push(HistoricalVersion {
+            extension,
+            grammars: grammars.to_vec(),
+        });
+
+        write_json(&path, &history).await?;
+        Ok(true)
+    }
+
+    /// Keeps a copy of the lockfile for this version of the extension, and points the extension at it.
+    async fn store_history_lockfile(
+        &self,
+        extension: &mut Extension,
+        lockfile: &Path,
+    ) -> anyhow::Result<()> {
+        let name = &extension.name;
+        let version = &extension.version;
+
+        if let ExtensionKind::Rust {
+            cargo_lock: Some(cargo_lock),
+            ..
+        } = &mut extension.kind
+            && let Ok(lockfile) = fs::read(lockfile).await
+        {
+            fs::create_dir_all(self.versions_dir().join(name)).await?;
+            write_if_changed(&self.history_lockfile(name, version), &lockfile).await?;
+
+            cargo_lock.lock_file =
+                PathBuf::from(format!("/generated/versions/{name}/{version}.lock"));
+        }
+
+        Ok(())
+    }
+
+    /// Records a removed extension as a tombstone, keeping its lockfile and version history from `from`.
+    pub async fn bury(
+        &self,
+        extension: &Extension,
+        grammars: &[Grammar],
+        from: &Self,
+    ) -> anyhow::Result<()> {
+        let name = &extension.name;
+        let mut extension = extension.clone();
+
+        if let ExtensionKind::Rust {
+            cargo_lock: Some(cargo_lock),
+            ..
+        } = &mut extension.kind
+            && let Ok(lockfile) = fs::read(from.lockfile(name)).await
+        {
+            fs::create_dir_all(self.tombstones_dir()).await?;
+            write_if_changed(&self.tombstone_lockfile(name), &lockfile).await?;
+
+            cargo_lock.lock_file = PathBuf::from(format!("/generated/tombstones/{name}.lock"));
+        }
+
+        let tombstone = Tombstone {
+            name: name.clone(),
+            removed: jiff::Timestamp::now().to_string(),
+            extension,
+            grammars: grammars.to_vec(),
+        };
+
+        fs::create_dir_all(self.tombstones_dir()).await?;
+        write_json(&self.tombstone(name), &tombstone).await?;
+
+        // Kept versions are what's left to build the extension from.
+        if self.root() != from.root() && !self.history(name).exists() {
+            self.copy_history(from, name).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Names of every extension with a tombstone.
+    pub async fn tombstones(&self) -> anyhow::Result<Vec<String>> {
+        let mut names = vec![];
+
+        let tombstones_dir = self.tombstones_dir();
+        if tombstones_dir.exists() {
+            let mut entries = fs::read_dir(&tombstones_dir).await?;
+            while let Some(entry) = entries.try_next().await? {
+                let file_name = entry.file_name();
+                if let Some(name) = file_name.to_string_lossy().strip_suffix(".json") {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+
+        Ok(names)
+    }
+
+    /// Writes the alias map of renamed extensions, adding `renames` to the aliases already in `from`.
+    ///
+    /// Aliases are dropped once their old name is back, or the extension they point to is gone.
+    pub async fn write_aliases(
+        &self,
+        from: &Self,
+        output: &NixExtensions,
+        renames: &BTreeMap<String, String>,
+    ) -> anyhow::Result<()> {
+        let mut aliases: BTreeMap<String, String> = if from.aliases().exists() {
+            serde_json::from_str(&fs::read_to_string(from.aliases()).await?)?
+        } else {
+            BTreeMap::new()
+        };
+
+        // Follow extensions that were renamed again.
+        for new in aliases.values_mut() {
+            if let Some(newer) = renames.get(new) {
+                new.clone_from(newer);
+            }
+        }
+
+        for (old, new) in renames {
+            tracing::info!(old = old, new = new, "Found renamed extension");
+            aliases.insert(old.clone(), new.clone());
+        }
+
+        aliases.retain(|old, new| {
+            !output
+                .extensions
+                .iter()
+                .any(|extension| extension.name == *old)
+                && output
+                    .extensions
+                    .iter()
+                    .any(|extension| extension.name == *new)
+        });
+
+        write_json(&self.aliases(), &aliases).await?;
+        Ok(())
+    }
+
+    /// Copies the tombstone of `name` from another directory, if it has one.
+    pub async fn copy_tombstone(&self, from: &Self, name: &str) -> anyhow::Result<()> {
+        let Some(tombstone) = Tombstone::load(&from.tombstone(name)).await? else {
+            return Ok(());
+        };
+
+        fs::create_dir_all(self.tombstones_dir()).await?;
+        write_json(&self.tombstone(name), &tombstone).await?;
+
+        let source = from.tombstone_lockfile(name);
+        if source.exists() {
+            let lockfile = fs::read(source).await?;
+            write_if_changed(&self.tombstone_lockfile(name), &lockfile).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Copies the version history of `name` from another directory, if it has one.
+    pub async fn copy_history(&self, from: &Self, name: &str) -> anyhow::Result<()> {
+        let Some(history) = VersionHistory::load(&from.history(name)).await? else {
+            return Ok(());
+        };
+
+        fs::create_dir_all(self.versions_dir()).await?;
+        write_json(&self.history(name), &history).await?;
+
+        for version in &history.versions {
+            let version = &version.extension.version;
+            let source = from.history_lockfile(name, version);
+            if source.exists() {
+                let lockfile = fs::read(source).await?;
+                fs::create_dir_all(self.versions_dir().join(name)).await?;
+                write_if_changed(&self.history_lockfile(name, version), &lockfile).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Records the sync metadata, stamped with when this sync ran.
+    pub async fn write_metadata(&self, metadata: &SyncMetadata) -> anyhow::Result<()> {
+        write_json(&self.metadata(), metadata).await?;
+        Ok(())
+    }
+
+    /// Copies the stored lockfile of `name` from another directory, if it has one.
+    pub async fn copy_lockfile(&self, from: &Self, name: &str) -> anyhow::Result<()> {
+        let source = from.lockfile(name);
+        if source.exists() {
+            let lockfile = fs::read(source).await?;
+            write_if_changed(&self.lockfile(name), &lockfile).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Serializes `value` with its `src` replaced by the key of its entry in the shared source table.
+fn with_source_key(value: &impl Serialize, src: &Source) -> anyhow::Result<Value> {
+    let mut value = serde_json::to_value(value)?;
+    value["src"] = Value::String(src.key());
+    Ok(value)
+}
+
+/// Parses an extension or grammar file, looking up its `src` key in the shared source table.
+///
+/// Files from before the table existed embed their source, and are read as is.
+fn resolve_source<T: DeserializeOwned>(
+    path: &Path,
+    content: &str,
+    sources: &BTreeMap<String, Value>,
+) -> Option<T> {
+    let mut value: Value = serde_json::from_str(content).ok()?;
+    if let Some(key) = value["src"].as_str() {
+        let Some(src) = sources.get(key) else {
+            tracing::warn!(path = %path.display(), key = key, "Missing source");
+            return None;
+        };
+
+        value["src"] = src.clone();
+    }
+
+    serde_json::from_value(value).ok()
+}
+
+/// Serializes `value` as pretty JSON with a trailing newline and writes it to `path`.
+pub async fn write_json(path: &Path, value: &(impl Serialize + Sync)) -> anyhow::Result<bool> {
+    let mut json = serde_json::to_string_pretty(value)?;
+    json.push('\n');
+
+    write_if_changed(path, json.as_bytes()).await
+}
+
+/// Removes the temporary files in `dir` left behind by [`write_if_changed`] when a run was killed.
+async fn remove_temp_files(dir: &Path) -> anyhow::Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+
+    let mut existing = fs::read_dir(dir).await?;
+    while let Some(entry) = existing.try_next().await? {
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        if file_name.starts_with('.') && file_name.ends_with(".tmp") {
+            tracing::info!(path = ?entry.path(), "Removing leftover temporary file");
+            fs::remove_file(entry.path()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes `contents` to `path`, unless the file already holds exactly those bytes.
+///
+/// The data is written to a hidden sibling file first, then renamed into place.
+/// Returns whether the file was written.
+pub async fn write_if_changed(path: &Path, contents: &[u8]) -> anyhow::Result<bool> {
+    if fs::read(path)
+        .await
+        .is_ok_and(|existing| existing == contents)
+    {
+        return Ok(false);
+    }
+
+    let tmp = temp_path(path)?;
+
+    let mut file = fs::File::create(&tmp).await?;
+    file.write_all(contents).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    if let Err(err) = fs::rename(&tmp, path).await {
+        fs::remove_file(&tmp).await.ok();
+        return Err(err.into());
+    }
+
+    Ok(true)
+}
+
+fn temp_path(path: &Path) -> anyhow::Result<PathBuf> {
+    let Some(file_name) = path.file_name() else {
+        anyhow::bail!("Invalid output path: {}", path.display());
+    };
+
+    let mut tmp = std::ffi::OsString::from(".");
+    tmp.push(file_name);
+    tmp.push(".tmp");
+
+    Ok(path.with_file_name(tmp))
+}
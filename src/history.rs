@@ -0,0 +1,78 @@
+//! Previously synced versions of each extension, kept alongside the latest.
+
+use std::{num::NonZero, path::Path};
+
+use serde::{Deserialize, Serialize};
+use smol::fs;
+
+use crate::output::{Extension, Grammar};
+
+/// Every kept version of a single extension.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionHistory {
+    /// Version of the extension in `extensions/<name>.json`.
+    pub latest: String,
+    /// Kept versions, most recently synced first.
+    pub versions: Vec<HistoricalVersion>,
+}
+
+/// An extension as it was synced, with the grammars it used at the time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoricalVersion {
+    pub extension: Extension,
+    pub grammars: Vec<Grammar>,
+}
+
+/// How many versions of each extension to keep, written as a number or `all`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepVersions {
+    Last(NonZero<usize>),
+    All,
+}
+
+impl KeepVersions {
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        if value == "all" {
+            return Ok(Self::All);
+        }
+
+        let Ok(count) = value.parse() else {
+            anyhow::bail!("Invalid version count: '{value}' (expected a positive number or 'all')");
+        };
+
+        Ok(Self::Last(count))
+    }
+}
+
+impl VersionHistory {
+    pub async fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let content = fs::read_to_string(path).await?;
+        Ok(Some(serde_json::from_str(&content)?))
+    }
+
+    /// Makes `version` the latest, replacing any earlier sync of the same version.
+    ///
+    /// Returns the versions that no longer fit within `keep`.
+    pub fn push(
+        &mut self,
+        version: HistoricalVersion,
+        keep: Option<KeepVersions>,
+    ) -> Vec<HistoricalVersion> {
+        self.latest.clone_from(&version.extension.version);
+        self.versions
+            .retain(|existing| existing.extension.version != version.extension.version);
+
+        self.versions.insert(0, version);
+
+        match keep {
+            Some(KeepVersions::Last(count)) if self.versions.len() > count.get() => {
+                self.versions.split_off(count.get())
+            }
+            _ => vec![],
+        }
+    }
+}
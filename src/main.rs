@@ -1,31 +1,81 @@
 use std::{
-    collections::{BTreeMap, HashSet},
-    env::temp_dir,
+    collections::{BTreeMap, BTreeSet, HashSet},
     num::NonZero,
     path::{Path, PathBuf},
-    process::Command,
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 use futures_util::stream::FuturesUnordered;
-use registry::RegistryExtension;
 use smol::fs::unix;
-use smol::{fs, lock::Semaphore, stream::StreamExt};
+use smol::{Timer, fs, future, lock::Semaphore, stream::StreamExt};
 use smol_macros::main;
 use tracing::Instrument;
 
 use crate::{
-    manifest::ExtensionManifest, output::NixExtensions, registry::RegistryEntry,
-    sync::process_extension, wasm::extract_zed_api_version,
+    aliases::detect_renames,
+    custom::load_custom_extensions,
+    generated::GeneratedDir,
+    manifest::ExtensionManifest,
+    metadata::SyncMetadata,
+    options::{BackfillOptions, SyncOptions},
+    output::Extension,
+    overrides::Overrides,
+    registry::{RegistryRevision, load_registry, load_registry_sources, merge_registries},
+    report::SyncReport,
+    shutdown::Shutdown,
+    source::SourceCache,
+    sync::{ScratchDir, process_extension, resolve_remote_ref, scratch_dir},
+    validate::{validate_extension_name, validate_name, validate_registry_extension},
+    wasm::extract_zed_api_version,
 };
 
+pub mod aliases;
+pub mod backfill;
 pub mod copy;
+pub mod custom;
+pub mod generated;
+pub mod history;
 pub mod manifest;
+pub mod merge;
+pub mod metadata;
+pub mod options;
 pub mod output;
+pub mod overrides;
 pub mod registry;
+pub mod report;
+pub mod shard;
+pub mod shutdown;
+pub mod source;
 pub mod sync;
+pub mod tombstone;
+pub mod validate;
+pub mod verify;
 pub mod wasm;
 
+/// How long in-flight extensions may keep running after a shutdown is requested.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_mins(1);
+
+/// Outcome of waiting on the extensions being processed.
+enum Progress<T> {
+    Next(T),
+    Done,
+    Stopped,
+}
+
+/// Resolves once no new extensions should be started.
+async fn stop_scheduling(shutdown: &Shutdown, budget: Option<Instant>) {
+    let budget = async {
+        if let Some(budget) = budget {
+            Timer::at(budget).await;
+        } else {
+            future::pending::<()>().await;
+        }
+    };
+
+    future::or(shutdown.wait(), budget).await;
+}
+
 main! {
     async fn main() -> anyhow::Result<()> {
         run().await
@@ -42,154 +92,138 @@ async fn run() -> anyhow::Result<()> {
     let args: Vec<String> = std::env::args().collect();
     match args.get(1).map(String::as_str) {
         Some("sync") => {
-            let mut output = NixExtensions::default();
-
-            // Load existing extensions
-            let extensions_dir = Path::new("generated/extensions");
-            if extensions_dir.exists() {
-                tracing::info!("Loading existing extensions");
-
-                let mut entries = fs::read_dir(extensions_dir).await?;
-                while let Some(entry) = entries.try_next().await? {
-                    let path = entry.path();
-                    if path
-                        .extension()
-                        .is_some_and(|extension| extension == "json")
-                    {
-                        let content = fs::read_to_string(&path).await?;
-                        if let Ok(extension) = serde_json::from_str(&content) {
-                            output.extensions.push(extension);
-                        }
-                    }
-                }
-            }
+            let options = SyncOptions::parse(&args[2..])?;
+            let budget = options.time_budget.map(|budget| Instant::now() + budget);
 
-            // Load existing grammars
-            let grammars_dir = Path::new("generated/grammars");
-            if grammars_dir.exists() {
-                tracing::info!("Loading existing grammars");
+            let _scratch = ScratchDir;
 
-                let mut entries = fs::read_dir(grammars_dir).await?;
-                while let Some(entry) = entries.try_next().await? {
-                    let path = entry.path();
-                    if path
-                        .extension()
-                        .is_some_and(|extension| extension == "json")
-                    {
-                        let content = fs::read_to_string(&path).await?;
-                        if let Ok(grammar) = serde_json::from_str(&content) {
-                            output.grammars.push(grammar);
-                        }
-                    }
-                }
-            }
+            let shutdown = Shutdown::default();
+            shutdown::listen_for_signals(shutdown.clone())?;
 
-            tracing::info!("Cloning extensions registry");
+            let generated = GeneratedDir::new("generated");
+            let target = GeneratedDir::new(options.output_dir());
 
-            let tmp_registry = temp_dir().join("registry");
-            if tmp_registry.exists() {
-                fs::remove_dir_all(&tmp_registry).await?;
-            }
+            let mut output = generated.load().await?;
+            let mut previous_report = SyncReport::load(&generated.report()).await?;
 
-            let clone = Command::new("git")
-                .args([
-                    "clone",
-                    "--depth",
-                    "1",
-                    "https://github.com/zed-industries/extensions",
-                    &tmp_registry.to_string_lossy(),
-                ])
-                .status()?;
-
-            if !clone.success() {
-                anyhow::bail!("Failed to clone extensions repository");
-            }
+            // Extensions from before this sync, which can't be renames of the ones it removes.
+            let previous: HashSet<String> = output
+                .extensions
+                .iter()
+                .map(|extension| extension.name.clone())
+                .collect();
 
-            // Lookup registry extensions
-            let registry = tmp_registry.join("extensions.toml");
-            let registry = fs::read_to_string(registry).await?;
-            let registry: BTreeMap<String, RegistryEntry> = toml::from_str(&registry)?;
+            if target.root() != generated.root() {
+                // Resume an earlier run that was writing to the same output.
+                let resumed = target.load().await?;
+                for extension in resumed.extensions {
+                    let grammars = resumed
+                        .grammars
+                        .iter()
+                        .filter(|grammar| extension.grammars.contains(&grammar.id))
+                        .cloned()
+                        .collect();
 
-            // Parse submodule revisions
-            let submodules = Command::new("git")
-                .current_dir(&tmp_registry)
-                .args(["submodule", "status"])
-                .output()?;
+                    output.upsert(extension, grammars);
+                }
 
-            if !submodules.status.success() {
-                anyhow::bail!("Failed to get submodule status");
+                let report = SyncReport::load(&target.report()).await?;
+                previous_report.pending.extend(report.pending);
             }
 
-            let submodules = String::from_utf8(submodules.stdout)?.trim().to_owned();
+            let overrides_path = options
+                .overrides
+                .clone()
+                .unwrap_or_else(|| PathBuf::from("overrides.toml"));
 
-            let mut revisions: BTreeMap<String, String> = BTreeMap::new();
-            for line in submodules.lines() {
-                let parts: Vec<&str> = line.splitn(2, ' ').collect();
+            let overrides = Overrides::load(&overrides_path).await?;
 
-                let revision = parts[0].trim_start_matches('-').to_owned();
-                let path = parts[1].to_owned();
+            let custom_path = options
+                .custom
+                .clone()
+                .unwrap_or_else(|| PathBuf::from("custom-extensions.toml"));
 
-                revisions.insert(path, revision);
-            }
+            let custom_extensions = load_custom_extensions(&custom_path).await?;
 
-            // Parse submodule repositories
-            let gitmodules = Command::new("git")
-                .current_dir(&tmp_registry)
-                .args(["config", "--file", ".gitmodules", "--list"])
-                .output()?;
+            let registries_path = options
+                .registries
+                .clone()
+                .unwrap_or_else(|| PathBuf::from("registries.toml"));
 
-            if !gitmodules.status.success() {
-                anyhow::bail!("Failed to get submodule repositories");
+            let sources = load_registry_sources(&registries_path).await?;
+            if matches!(options.registry_revision, Some(RegistryRevision::Rev(_)))
+                && sources.len() > 1
+            {
+                anyhow::bail!("'--registry-rev' can only be used with a single registry");
             }
 
-            let gitmodules = String::from_utf8(gitmodules.stdout)?.trim().to_owned();
+            let mut registries = vec![];
+            for (name, source) in sources {
+                let dir = scratch_dir().join("registries").join(&name);
+                let registry =
+                    load_registry(&name, &source, &dir, options.registry_revision.as_ref()).await?;
 
-            let mut repositories: BTreeMap<String, String> = BTreeMap::new();
-            for line in gitmodules.lines() {
-                let parts: Vec<&str> = line.splitn(2, '=').collect();
-
-                let path = parts[0]
-                    .trim_start_matches("submodule.")
-                    .trim_end_matches(".url")
-                    .to_owned();
+                registries.push(registry);
+            }
 
-                let repository = parts[1].trim_end_matches(".git").to_owned();
-                repositories.insert(path, repository);
+            let (mut extensions, collisions) = merge_registries(&registries);
+            for (name, registries) in &collisions {
+                tracing::warn!(
+                    name = name,
+                    registries = ?registries,
+                    "Extension listed by multiple registries, using the first"
+                );
             }
 
-            // Merge details
-            let mut extensions: Vec<RegistryExtension> = vec![];
-            for (name, entry) in &registry {
-                let Some(repository) = repositories.get(&entry.submodule) else {
+            // Add custom extensions
+            for custom in &custom_extensions {
+                if let Some(index) = extensions
+                    .iter()
+                    .position(|extension| extension.name == custom.name)
+                {
                     tracing::warn!(
-                        submodule = ?entry.submodule,
-                        "Missing submodule repository"
+                        name = custom.name,
+                        "Custom extension replaces registry extension"
                     );
 
-                    continue;
-                };
+                    extensions.remove(index);
+                }
 
-                let Some(revision) = revisions.get(&entry.submodule) else {
-                    tracing::warn!(
-                        submodule = ?entry.submodule,
-                        "Missing submodule revision"
-                    );
+                extensions.push(custom.clone());
+            }
 
-                    continue;
+            // Reject entries that can't safely be used as file names
+            let mut failures = BTreeMap::new();
+            extensions.retain(|extension| {
+                let Err(err) = validate_registry_extension(extension) else {
+                    return true;
                 };
 
-                extensions.push(RegistryExtension {
-                    name: name.clone(),
-                    version: entry.version.clone(),
-                    repository: repository.clone(),
-                    path: entry.path.clone(),
-                    rev: revision.clone(),
-                });
+                tracing::error!(name = extension.name, err = %err, "Rejecting extension");
+                failures.insert(extension.name.clone(), err.to_string());
+                false
+            });
+
+            // Record where existing extensions come from
+            for existing in &mut output.extensions {
+                if existing.registry.is_none() {
+                    existing.registry = extensions
+                        .iter()
+                        .find(|extension| extension.name == existing.name)
+                        .map(|extension| extension.registry.clone());
+                }
             }
 
-            let extension_names: HashSet<String> = registry
+            // Apply local overrides
+            // Extensions with a local source are compared by source rather than version.
+            let mut pinned = overrides.apply(&mut extensions);
+            pinned.extend(custom_extensions.iter().map(|custom| custom.name.clone()));
+
+            let extension_names: HashSet<String> = registries
                 .iter()
-                .map(|extension| extension.0.clone())
+                .flat_map(|registry| &registry.names)
+                .chain(custom_extensions.iter().map(|custom| &custom.name))
+                .cloned()
                 .collect();
 
             // Handle removed extensions/grammars
@@ -200,16 +234,31 @@ async fn run() -> anyhow::Result<()> {
                 .map(|ext| ext.name.clone())
                 .collect();
 
+            let mut buried = vec![];
             for name in &removed_extensions {
+                // Older registry revisions lack anything added since, which is dropped rather than tombstoned.
+                if options.registry_revision.is_some() {
+                    tracing::info!(
+                        name = name,
+                        "Dropping extension missing from registry revision"
+                    );
+                    continue;
+                }
+
                 tracing::info!(
                     name = name,
                     "Removing extension that is no longer maintained"
                 );
 
                 if let Some(extension) = output.extensions.iter().find(|e| &e.name == name) {
-                    output
+                    let grammars = output
                         .grammars
-                        .retain(|grammar| !extension.grammars.contains(&grammar.id));
+                        .iter()
+                        .filter(|grammar| extension.grammars.contains(&grammar.id))
+                        .cloned()
+                        .collect::<Vec<_>>();
+
+                    buried.push((extension.clone(), grammars));
                 }
             }
 
@@ -217,8 +266,83 @@ async fn run() -> anyhow::Result<()> {
                 .extensions
                 .retain(|existing| !removed_extensions.contains(&existing.name));
 
+            // Grammars can be shared, so only drop those no other extension uses.
+            output.prune_grammars();
+
+            // Extensions and grammars from the same source share a single pre-fetch.
+            // Created before sharding, so every shard covers the same extensions with a shared source.
+            let cache = SourceCache::new(&extensions);
+
+            // Sources used to be fetched per extension, and only one can stay under a shared key.
+            let sources = output.sources();
+            let uncovered: HashSet<String> = output
+                .extensions
+                .iter()
+                .filter(|existing| {
+                    !sources
+                        .get(&existing.src.key())
+                        .is_some_and(|src| src.covers(&existing.src.sparse_checkout))
+                })
+                .map(|existing| existing.name.clone())
+                .collect();
+
+            // Only keep this shard's part of the registry
+            if let Some(shard) = options.shard {
+                tracing::info!(shard = %shard, "Processing shard");
+
+                extensions.retain(|extension| shard.contains(&extension.name));
+                output
+                    .extensions
+                    .retain(|extension| shard.contains(&extension.name));
+
+                let ids: HashSet<&String> = output
+                    .extensions
+                    .iter()
+                    .flat_map(|extension| &extension.grammars)
+                    .collect();
+
+                output.grammars.retain(|grammar| ids.contains(&grammar.id));
+            }
+
+            // Pinned tags and branches can move, so look up the commit they point at now.
+            let mut moved = HashSet::new();
+            for extension in &extensions {
+                let Some(existing) = output
+                    .extensions
+                    .iter()
+                    .find(|existing| existing.name == extension.name)
+                else {
+                    continue;
+                };
+
+                if !pinned.contains(&extension.name)
+                    || existing.rev.as_ref() != Some(&extension.rev)
+                    || existing.src.rev == extension.rev
+                {
+                    continue;
+                }
+
+                match resolve_remote_ref(&extension.repository, &extension.rev).await {
+                    Ok(Some(resolved)) if resolved.commit != existing.src.rev => {
+                        tracing::info!(
+                            name = extension.name,
+                            rev = extension.rev,
+                            commit = resolved.commit,
+                            "Pinned revision moved"
+                        );
+                        moved.insert(extension.name.clone());
+                    }
+                    Ok(_) => (),
+                    Err(err) => tracing::warn!(
+                        name = extension.name,
+                        err = %err,
+                        "Failed to check pinned revision"
+                    ),
+                }
+            }
+
             // Filter remaining extensions/grammars
-            let extensions = extensions
+            let mut extensions = extensions
                 .into_iter()
                 .filter(|extension| {
                     // Skip extension that haven't changed.
@@ -227,7 +351,28 @@ async fn run() -> anyhow::Result<()> {
                         .iter()
                         .find(|existing| existing.name == extension.name)
                     {
-                        if existing.version >= extension.version {
+                        let unchanged = if !output.has_grammars(existing)
+                            || uncovered.contains(&existing.name)
+                        {
+                            false
+                        } else if existing.registry.as_ref() != Some(&extension.registry)
+                            || existing.src.url != extension.repository
+                        {
+                            // Another registry or repository took over, whatever its version.
+                            false
+                        } else if pinned.contains(&extension.name) {
+                            // Compared as given, as tags and branches never match the commit they resolve to.
+                            existing.rev.as_ref().unwrap_or(&existing.src.rev) == &extension.rev
+                                && existing.path == extension.path
+                                && !moved.contains(&extension.name)
+                        } else if options.registry_revision.is_some() {
+                            // Older registry revisions can hold older versions.
+                            existing.version == extension.version
+                        } else {
+                            existing.version >= extension.version
+                        };
+
+                        if unchanged {
                             tracing::debug!(name = extension.name, "Skipping unchanged extension");
                             return false;
                         }
@@ -239,12 +384,60 @@ async fn run() -> anyhow::Result<()> {
                 })
                 .collect::<Vec<_>>();
 
+            // Pick up where the previous run left off.
+            extensions.sort_by_key(|extension| !previous_report.pending.contains(&extension.name));
+
+            let mut remaining: BTreeSet<String> = extensions
+                .iter()
+                .map(|extension| extension.name.clone())
+                .collect();
+
+            target.create().await?;
+
+            // Keep removed extensions on record.
+            for (extension, grammars) in &buried {
+                if options
+                    .shard
+                    .is_none_or(|shard| shard.contains(&extension.name))
+                {
+                    target.bury(extension, grammars, &generated).await?;
+                }
+            }
+
+            // Carry over lockfiles and history, recording the current version of each extension.
+            for extension in &output.extensions {
+                let name = &extension.name;
+                if target.root() != generated.root() {
+                    if !target.history(name).exists() {
+                        target.copy_history(&generated, name).await?;
+                    }
+
+                    if !target.lockfile(name).exists() {
+                        target.copy_lockfile(&generated, name).await?;
+                    }
+                }
+
+                let grammars: Vec<_> = output
+                    .grammars
+                    .iter()
+                    .filter(|grammar| extension.grammars.contains(&grammar.id))
+                    .cloned()
+                    .collect();
+
+                target
+                    .record_version(extension, &grammars, options.keep_versions)
+                    .await?;
+            }
+
             let limit = std::thread::available_parallelism().map_or(1, NonZero::get) * 2;
             let semaphore = Arc::new(Semaphore::new(limit));
 
             let mut futures = FuturesUnordered::new();
             for extension in extensions {
                 let semaphore = Arc::clone(&semaphore);
+                let shutdown = shutdown.clone();
+                let target = target.clone();
+                let cache = cache.clone();
 
                 let span = tracing::info_span!(
                     "process_extension",
@@ -253,113 +446,205 @@ async fn run() -> anyhow::Result<()> {
                 );
 
                 let future = async move {
-                    let _acquire = semaphore.acquire().await;
-                    process_extension(extension).instrument(span).await
+                    // Don't start new extensions once a shutdown is requested or the budget is spent.
+                    let _acquire = future::or(async { Some(semaphore.acquire().await) }, async {
+                        stop_scheduling(&shutdown, budget).await;
+                        None
+                    })
+                    .await?;
+
+                    // A permit can be freed after that, which `future::or` still prefers.
+                    if shutdown.is_requested()
+                        || budget.is_some_and(|budget| Instant::now() >= budget)
+                    {
+                        return None;
+                    }
+
+                    let name = extension.name.clone();
+                    let result = process_extension(extension, &target, &cache)
+                        .instrument(span)
+                        .await;
+                    Some((name, result))
                 };
 
                 futures.push(future);
             }
 
-            while let Some(result) = futures.next().await {
+            let mut deadline = None;
+            loop {
+                let progress = future::or(
+                    async { futures.next().await.map_or(Progress::Done, Progress::Next) },
+                    async {
+                        if let Some(deadline) = deadline {
+                            Timer::at(deadline).await;
+                        } else {
+                            shutdown.wait().await;
+                        }
+
+                        Progress::Stopped
+                    },
+                )
+                .await;
+
+                let (name, result) = match progress {
+                    Progress::Next(Some(result)) => result,
+                    Progress::Next(None) => continue,
+                    Progress::Done => break,
+                    Progress::Stopped if deadline.is_none() => {
+                        tracing::warn!(
+                            grace_period = ?SHUTDOWN_GRACE_PERIOD,
+                            "Waiting for in-flight extensions to finish"
+                        );
+
+                        deadline = Some(Instant::now() + SHUTDOWN_GRACE_PERIOD);
+                        continue;
+                    }
+                    Progress::Stopped => {
+                        tracing::warn!(
+                            remaining = futures.len(),
+                            "Grace period expired, cancelling in-flight extensions"
+                        );
+
+                        break;
+                    }
+                };
+
+                remaining.remove(&name);
+
                 match result {
                     Ok(Some((extension, grammars))) => {
-                        // Remove outdated extensions and grammars from output.
-                        if let Some(outdated) = output
-                            .extensions
-                            .iter()
-                            .find(|existing| existing.name == extension.name)
-                            .map(|existing| existing.grammars.clone())
-                        {
-                            output
-                                .grammars
-                                .retain(|grammar| !outdated.contains(&grammar.id));
-
-                            output
-                                .extensions
-                                .retain(|existing| existing.name != extension.name);
+                        if let Some(id) = output.grammar_conflict(&grammars) {
+                            tracing::error!(
+                                id = id,
+                                "Grammar id is already used by a grammar from a different source"
+                            );
+
+                            failures.insert(
+                                name,
+                                format!("Grammar id '{id}' is already used by a different grammar"),
+                            );
+
+                            continue;
                         }
 
-                        output.extensions.push(extension);
-                        output.grammars.extend(grammars);
+                        // Checkpoint, so an interrupted sync can resume from here.
+                        let outdated = output.outdated_grammars(&extension.name);
+                        target
+                            .checkpoint_extension(&extension, &grammars, &outdated)
+                            .await?;
+
+                        target
+                            .record_version(&extension, &grammars, options.keep_versions)
+                            .await?;
+
+                        output.upsert(extension, grammars);
                     }
                     Ok(_) => (),
-                    Err(err) => tracing::error!(
-                        err = ?err,
-                        "Error processing extension"
-                    ),
+                    Err(err) => {
+                        tracing::error!(
+                            err = ?err,
+                            "Error processing extension"
+                        );
+
+                        failures.insert(name, format!("{err:#}"));
+                    }
                 }
             }
 
-            tracing::info!("Writing output");
+            // Cancels anything still running.
+            drop(futures);
+
+            if !remaining.is_empty() {
+                tracing::warn!(
+                    count = remaining.len(),
+                    extensions = ?remaining,
+                    "Extensions left unprocessed"
+                );
+            }
+
+            let report = SyncReport {
+                pending: remaining.into_iter().collect(),
+                collisions,
+                failures,
+                needs_generate: output.needs_generate(),
+                unused_grammars: output.unused_grammars(),
+                undeclared_grammars: output.undeclared_grammars(),
+            };
+
+            generated::write_json(&target.report(), &report).await?;
+
+            if let Some(shard) = options.shard {
+                generated::write_json(&target.shard(), &shard).await?;
+            }
+
+            tracing::info!(
+                extensions = output.extensions.len(),
+                grammars = output.grammars.len(),
+                "Writing output"
+            );
 
             output.extensions.sort_by(|a, b| a.name.cmp(&b.name));
             output.grammars.sort_by(|a, b| a.id.cmp(&b.id));
 
-            // Write extension files
-            if !extensions_dir.exists() {
-                fs::create_dir_all(extensions_dir).await?;
+            let supported = options.grammar_abi();
+            for (extension, grammar) in output.unsupported_grammars(&supported) {
+                tracing::warn!(
+                    extension = extension,
+                    grammar = grammar.id,
+                    abi = grammar.abi_version,
+                    supported = ?supported,
+                    "Grammar ABI version is not supported"
+                );
             }
 
-            let mut existing = fs::read_dir(extensions_dir).await?;
-            while let Some(entry) = existing.try_next().await? {
-                let file_name = entry.file_name();
-                let file_name = file_name.to_string_lossy();
+            target.write(&output).await?;
 
-                if let Some(name) = file_name.strip_suffix(".json")
-                    && !output
-                        .extensions
-                        .iter()
-                        .any(|extension| extension.name == name)
-                {
-                    tracing::info!(name = name, "Removing stale extension file");
-                    fs::remove_file(entry.path()).await?;
-                }
+            // Renames can span shards, so sharded runs leave this to `merge`.
+            if options.shard.is_none() {
+                let removed: Vec<&Extension> =
+                    buried.iter().map(|(extension, _)| extension).collect();
+                let added: Vec<&Extension> = output
+                    .extensions
+                    .iter()
+                    .filter(|extension| !previous.contains(&extension.name))
+                    .collect();
 
-                if let Some(name) = file_name.strip_suffix(".lock")
-                    && !output
-                        .extensions
-                        .iter()
-                        .any(|extension| extension.name == name)
-                {
-                    tracing::info!(name = name, "Removing stale lockfile");
-                    fs::remove_file(entry.path()).await?;
-                }
+                let renames = detect_renames(&removed, &added);
+                target.write_aliases(&generated, &output, &renames).await?;
             }
+            target
+                .write_metadata(&SyncMetadata::new(&registries))
+                .await?;
 
-            for extension in &output.extensions {
-                let name = &extension.name;
-                let path = extensions_dir.join(format!("{name}.json"));
-                let json = serde_json::to_string_pretty(&extension)?;
-                fs::write(path, json).await?;
+            if shutdown.is_requested() {
+                anyhow::bail!("Sync interrupted, partial results written");
             }
+        }
 
-            // Write grammar files
-            if !grammars_dir.exists() {
-                fs::create_dir_all(grammars_dir).await?;
-            }
+        Some("backfill") => {
+            let options = BackfillOptions::parse(&args[2..])?;
 
-            let mut existing = fs::read_dir(grammars_dir).await?;
-            while let Some(entry) = existing.try_next().await? {
-                let id = entry
-                    .file_name()
-                    .to_string_lossy()
-                    .trim_end_matches(".json")
-                    .to_owned();
+            let _scratch = ScratchDir;
 
-                if !output.grammars.iter().any(|grammar| grammar.id == id) {
-                    tracing::info!(id = id, "Removing stale grammar file");
-                    fs::remove_file(entry.path()).await?;
-                }
+            let generated = GeneratedDir::new("generated");
+            let registries_path = options
+                .registries
+                .unwrap_or_else(|| PathBuf::from("registries.toml"));
+            let sources = load_registry_sources(&registries_path).await?;
+            for name in &options.names {
+                validate_extension_name(name)?;
+                backfill::backfill_extension(name, &sources, &generated).await?;
             }
+        }
 
-            for grammar in &output.grammars {
-                let id = &grammar.id;
-                let path = grammars_dir.join(format!("{id}.json"));
-                let json = serde_json::to_string_pretty(&grammar)?;
-                fs::write(path, json).await?;
-            }
+        Some("merge") => {
+            let shards: Vec<PathBuf> = args[2..].iter().map(PathBuf::from).collect();
+            merge::merge_shards(&shards, &GeneratedDir::new("generated")).await?;
+        }
 
-            fs::remove_dir_all(tmp_registry).await?;
+        Some("verify") => {
+            let dir = args.get(2).map_or("generated", String::as_str);
+            verify::verify(&GeneratedDir::new(dir)).await?;
         }
 
         Some("populate") => {
@@ -453,6 +738,7 @@ async fn run() -> anyhow::Result<()> {
 
             let manifest = fs::read_to_string(manifest_path).await?;
             let manifest: ExtensionManifest = toml::from_str(&manifest)?;
+            validate_name(&manifest.id)?;
 
             let extension_dir = out.join("share/zed/extensions").join(&manifest.id);
             fs::create_dir_all(&extension_dir).await?;
@@ -88,3 +88,14 @@ pub struct GrammarManifestEntry {
     #[serde(flatten)]
     _other: BTreeMap<String, Value>,
 }
+
+/// A language's `config.toml`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LanguageConfig {
+    pub name: String,
+    #[serde(default)]
+    pub grammar: Option<String>,
+
+    #[serde(flatten)]
+    _other: BTreeMap<String, Value>,
+}
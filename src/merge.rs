@@ -0,0 +1,192 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+};
+
+use smol::fs;
+
+use crate::{
+    aliases::detect_renames,
+    generated::{self, GeneratedDir},
+    metadata::SyncMetadata,
+    output::{Extension, NixExtensions},
+    report::SyncReport,
+    shard::Shard,
+    tombstone::Tombstone,
+};
+
+/// Combines the outputs of `sync --shard` runs into `into`, removing anything no shard produced.
+///
+/// Refuses to write anything if a shard is missing, or if two shards disagree.
+pub async fn merge_shards(dirs: &[PathBuf], into: &GeneratedDir) -> anyhow::Result<()> {
+    if dirs.is_empty() {
+        anyhow::bail!("Missing shard directories");
+    }
+
+    let mut problems = vec![];
+
+    let mut total = None;
+    let mut shards: BTreeMap<u32, GeneratedDir> = BTreeMap::new();
+    for dir in dirs {
+        let dir = GeneratedDir::new(dir);
+        let shard = fs::read_to_string(dir.shard()).await?;
+        let shard: Shard = serde_json::from_str(&shard)?;
+
+        if *total.get_or_insert(shard.total) != shard.total {
+            problems.push(format!(
+                "Shard {shard} in '{}' doesn't match the other shard totals",
+                dir.root().display()
+            ));
+        }
+
+        if let Some(existing) = shards.insert(shard.index, dir.clone()) {
+            problems.push(format!(
+                "Shard {shard} found in both '{}' and '{}'",
+                existing.root().display(),
+                dir.root().display()
+            ));
+        }
+    }
+
+    let total = total.unwrap_or_default();
+    for index in 1..=total {
+        if !shards.contains_key(&index) {
+            problems.push(format!("Missing shard {index}/{total}"));
+        }
+    }
+
+    let mut merged = NixExtensions::default();
+    let mut owners: BTreeMap<String, GeneratedDir> = BTreeMap::new();
+    let mut pending = BTreeSet::new();
+    let mut collisions = BTreeMap::new();
+    let mut failures = BTreeMap::new();
+    let mut metadata: Option<SyncMetadata> = None;
+
+    for (index, dir) in &shards {
+        tracing::info!(shard = index, dir = ?dir.root(), "Merging shard");
+
+        let output = dir.load().await?;
+        for extension in output.extensions {
+            if let Some(owner) = owners.get(&extension.name) {
+                problems.push(format!(
+                    "Extension '{}' found in both '{}' and '{}'",
+                    extension.name,
+                    owner.root().display(),
+                    dir.root().display()
+                ));
+
+                continue;
+            }
+
+            owners.insert(extension.name.clone(), dir.clone());
+            merged.extensions.push(extension);
+        }
+
+        for grammar in output.grammars {
+            match merged
+                .grammars
+                .iter()
+                .find(|existing| existing.id == grammar.id)
+            {
+                Some(existing)
+                    if existing.src != grammar.src
+                        || existing.grammar_root != grammar.grammar_root =>
+                {
+                    problems.push(format!("Grammar '{}' differs between shards", grammar.id));
+                }
+                Some(_) => (),
+                None => merged.grammars.push(grammar),
+            }
+        }
+
+        let report = SyncReport::load(&dir.report()).await?;
+        pending.extend(report.pending);
+        collisions.extend(report.collisions);
+        failures.extend(report.failures);
+
+        if let Some(shard_metadata) = SyncMetadata::load(&dir.metadata()).await? {
+            match &metadata {
+                Some(metadata) if !metadata.same_inputs(&shard_metadata) => {
+                    tracing::warn!(
+                        shard = index,
+                        "Shard was synced from different registry commits"
+                    );
+                }
+                Some(_) => (),
+                None => metadata = Some(shard_metadata),
+            }
+        }
+    }
+
+    if !problems.is_empty() {
+        for problem in &problems {
+            tracing::error!("{problem}");
+        }
+
+        anyhow::bail!("Failed to merge {} shard(s)", shards.len());
+    }
+
+    tracing::info!(
+        extensions = merged.extensions.len(),
+        grammars = merged.grammars.len(),
+        "Writing merged output"
+    );
+
+    merged.extensions.sort_by(|a, b| a.name.cmp(&b.name));
+    merged.grammars.sort_by(|a, b| a.id.cmp(&b.id));
+
+    // Extensions from before these shards ran, which can't be renames of the ones they removed.
+    let previous = into.load().await?;
+
+    // Tombstones go first, so writing keeps the history of their extensions.
+    let mut removed = vec![];
+    for dir in shards.values() {
+        for name in dir.tombstones().await? {
+            into.copy_tombstone(dir, &name).await?;
+            into.copy_history(dir, &name).await?;
+
+            if let Some(tombstone) = Tombstone::load(&dir.tombstone(&name)).await? {
+                removed.push(tombstone.extension);
+            }
+        }
+    }
+
+    into.write(&merged).await?;
+
+    for (name, owner) in &owners {
+        into.copy_lockfile(owner, name).await?;
+        into.copy_history(owner, name).await?;
+    }
+
+    let removed: Vec<&Extension> = removed.iter().collect();
+    let added: Vec<&Extension> = merged
+        .extensions
+        .iter()
+        .filter(|extension| {
+            !previous
+                .extensions
+                .iter()
+                .any(|existing| existing.name == extension.name)
+        })
+        .collect();
+
+    into.write_aliases(into, &merged, &detect_renames(&removed, &added))
+        .await?;
+
+    let report = SyncReport {
+        pending: pending.into_iter().collect(),
+        collisions,
+        failures,
+        needs_generate: merged.needs_generate(),
+        unused_grammars: merged.unused_grammars(),
+        undeclared_grammars: merged.undeclared_grammars(),
+    };
+
+    generated::write_json(&into.report(), &report).await?;
+
+    if let Some(metadata) = &metadata {
+        into.write_metadata(metadata).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use smol::fs;
+
+use crate::registry::Registry;
+
+/// Describes what a sync was built from, so it can be reproduced.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncMetadata {
+    pub registries: Vec<RegistryMetadata>,
+    /// The tool's version and the git revision it was built from, like `0.0.0+<commit>`.
+    #[serde(rename = "toolVersion")]
+    pub tool_version: String,
+    pub timestamp: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegistryMetadata {
+    pub name: String,
+    pub url: String,
+    pub rev: String,
+    pub date: String,
+}
+
+impl SyncMetadata {
+    #[must_use]
+    pub fn new(registries: &[Registry]) -> Self {
+        Self {
+            registries: registries
+                .iter()
+                .map(|registry| RegistryMetadata {
+                    name: registry.name.clone(),
+                    url: registry.url.clone(),
+                    rev: registry.rev.clone(),
+                    date: registry.date.clone(),
+                })
+                .collect(),
+            tool_version: concat!(
+                env!("CARGO_PKG_VERSION"),
+                "+",
+                env!("NIX_ZED_EXTENSIONS_REV")
+            )
+            .to_owned(),
+            timestamp: jiff::Timestamp::now().to_string(),
+        }
+    }
+
+    pub async fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let content = fs::read_to_string(path).await?;
+        Ok(Some(serde_json::from_str(&content)?))
+    }
+
+    /// Whether both syncs used the same inputs, regardless of when they ran.
+    #[must_use]
+    pub fn same_inputs(&self, other: &Self) -> bool {
+        self.registries == other.registries && self.tool_version == other.tool_version
+    }
+}
@@ -0,0 +1,218 @@
+use std::{ops::RangeInclusive, path::PathBuf, time::Duration};
+
+use crate::{history::KeepVersions, registry::RegistryRevision, shard::Shard};
+
+/// Grammar ABI versions the tree-sitter used by current Zed releases can load.
+const DEFAULT_GRAMMAR_ABI: RangeInclusive<u32> = 13..=15;
+
+/// Flags accepted by the `sync` command.
+#[derive(Debug, Default, Clone)]
+pub struct SyncOptions {
+    /// Stop starting new extensions once this much time has passed.
+    pub time_budget: Option<Duration>,
+    /// Only process the extensions that belong to this shard.
+    pub shard: Option<Shard>,
+    /// Where to write results, defaulting to `generated` or a per-shard directory.
+    pub output: Option<PathBuf>,
+    /// Overrides file to apply on top of the registry, defaulting to `overrides.toml`.
+    pub overrides: Option<PathBuf>,
+    /// Extensions to sync from outside the registry, defaulting to `custom-extensions.toml`.
+    pub custom: Option<PathBuf>,
+    /// Registries to sync from, defaulting to `registries.toml` or the official registry.
+    pub registries: Option<PathBuf>,
+    /// Sync from this point in the registry history, rather than its latest commit.
+    pub registry_revision: Option<RegistryRevision>,
+    /// Keep this many versions of each extension in `versions/`.
+    pub keep_versions: Option<KeepVersions>,
+    /// Grammar ABI versions to accept without a warning.
+    pub grammar_abi: Option<RangeInclusive<u32>>,
+}
+
+impl SyncOptions {
+    pub fn parse(args: &[String]) -> anyhow::Result<Self> {
+        let mut options = Self::default();
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| anyhow::anyhow!("Missing value for '{arg}'"))
+            };
+
+            match arg.as_str() {
+                "--time-budget" => options.time_budget = Some(parse_duration(value()?)?),
+                "--shard" => options.shard = Some(Shard::parse(value()?)?),
+                "--output" => options.output = Some(PathBuf::from(value()?)),
+                "--overrides" => options.overrides = Some(PathBuf::from(value()?)),
+                "--custom" => options.custom = Some(PathBuf::from(value()?)),
+                "--registries" => options.registries = Some(PathBuf::from(value()?)),
+                "--registry-rev" | "--registry-date" => {
+                    if options.registry_revision.is_some() {
+                        anyhow::bail!(
+                            "Only one of '--registry-rev' and '--registry-date' can be given"
+                        );
+                    }
+
+                    let value = value()?;
+                    options.registry_revision = Some(if arg == "--registry-rev" {
+                        RegistryRevision::Rev(value.clone())
+                    } else {
+                        RegistryRevision::parse_date(value)?
+                    });
+                }
+                "--keep-versions" => options.keep_versions = Some(KeepVersions::parse(value()?)?),
+                "--grammar-abi" => options.grammar_abi = Some(parse_abi_range(value()?)?),
+                _ => anyhow::bail!("Unknown sync argument: '{arg}'"),
+            }
+        }
+
+        Ok(options)
+    }
+
+    #[must_use]
+    pub fn output_dir(&self) -> PathBuf {
+        if let Some(output) = &self.output {
+            return output.clone();
+        }
+
+        match self.shard {
+            Some(shard) => PathBuf::from(format!("shards/{}-of-{}", shard.index, shard.total)),
+            None => PathBuf::from("generated"),
+        }
+    }
+
+    #[must_use]
+    pub fn grammar_abi(&self) -> RangeInclusive<u32> {
+        self.grammar_abi.clone().unwrap_or(DEFAULT_GRAMMAR_ABI)
+    }
+}
+
+/// Flags and extension names accepted by the `backfill` command.
+#[derive(Debug, Default, Clone)]
+pub struct BackfillOptions {
+    /// Extensions to add older versions of.
+    pub names: Vec<String>,
+    /// Registries the extensions come from, defaulting to `registries.toml` or the official registry.
+    pub registries: Option<PathBuf>,
+}
+
+impl BackfillOptions {
+    pub fn parse(args: &[String]) -> anyhow::Result<Self> {
+        let mut options = Self::default();
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--registries" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("Missing value for '{arg}'"))?;
+                    options.registries = Some(PathBuf::from(value));
+                }
+                _ if arg.starts_with('-') => anyhow::bail!("Unknown backfill argument: '{arg}'"),
+                _ => options.names.push(arg.clone()),
+            }
+        }
+
+        if options.names.is_empty() {
+            anyhow::bail!("Missing extension names");
+        }
+
+        Ok(options)
+    }
+}
+
+/// Parses ABI version ranges like `13-15`, or a single version like `14`.
+fn parse_abi_range(value: &str) -> anyhow::Result<RangeInclusive<u32>> {
+    let (min, max) = value.split_once('-').unwrap_or((value, value));
+
+    let (Ok(min), Ok(max)) = (min.parse(), max.parse()) else {
+        anyhow::bail!("Invalid ABI range: '{value}' (expected like 13-15)");
+    };
+
+    if min > max {
+        anyhow::bail!("Invalid ABI range: '{value}' (minimum is above maximum)");
+    }
+
+    Ok(min..=max)
+}
+
+/// Parses durations like `90`, `90s`, `45m` or `2h`.
+fn parse_duration(value: &str) -> anyhow::Result<Duration> {
+    let (number, unit) = value
+        .find(|char: char| !char.is_ascii_digit())
+        .map_or((value, ""), |index| value.split_at(index));
+
+    let Ok(number) = number.parse::<u64>() else {
+        anyhow::bail!("Invalid duration: '{value}'");
+    };
+
+    match unit {
+        "" | "s" => Ok(Duration::from_secs(number)),
+        "m" => Ok(Duration::from_mins(number)),
+        "h" => Ok(Duration::from_hours(number)),
+        _ => anyhow::bail!("Invalid duration unit: '{value}' (expected s, m or h)"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("45m").unwrap(), Duration::from_mins(45));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_hours(2));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("2d").is_err());
+        assert!(parse_duration("1h30m").is_err());
+    }
+
+    #[test]
+    fn parses_abi_ranges() {
+        assert_eq!(parse_abi_range("13-15").unwrap(), 13..=15);
+        assert_eq!(parse_abi_range("14").unwrap(), 14..=14);
+        assert!(parse_abi_range("15-13").is_err());
+        assert!(parse_abi_range("new").is_err());
+    }
+
+    #[test]
+    fn parses_sync_options() {
+        let options =
+            SyncOptions::parse(&args(&["--shard", "1/2", "--time-budget", "5m"])).unwrap();
+        assert_eq!(options.output_dir(), PathBuf::from("shards/1-of-2"));
+        assert_eq!(options.time_budget, Some(Duration::from_mins(5)));
+        assert_eq!(options.grammar_abi(), DEFAULT_GRAMMAR_ABI);
+
+        assert!(SyncOptions::parse(&args(&["--shard"])).is_err());
+        assert!(SyncOptions::parse(&args(&["--unknown"])).is_err());
+        assert!(
+            SyncOptions::parse(&args(&[
+                "--registry-rev",
+                "abc",
+                "--registry-date",
+                "2025-01-31"
+            ]))
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn parses_backfill_options() {
+        let options =
+            BackfillOptions::parse(&args(&["nix", "--registries", "other.toml", "rust"])).unwrap();
+        assert_eq!(options.names, ["nix", "rust"]);
+        assert_eq!(options.registries, Some(PathBuf::from("other.toml")));
+
+        assert!(BackfillOptions::parse(&args(&[])).is_err());
+        assert!(BackfillOptions::parse(&args(&["--registries", "other.toml"])).is_err());
+        assert!(BackfillOptions::parse(&args(&["nix", "--unknown"])).is_err());
+    }
+}
@@ -1,26 +1,269 @@
-use std::{collections::BTreeMap, path::PathBuf};
+use std::{collections::BTreeMap, ops::RangeInclusive, path::PathBuf};
 
 use serde::{Deserialize, Serialize};
 
+use crate::{shard::stable_hash, source::shared_sources};
+
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct NixExtensions {
     pub extensions: Vec<Extension>,
     pub grammars: Vec<Grammar>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl NixExtensions {
+    /// The source stored under each key, see [`shared_sources`].
+    #[must_use]
+    pub fn sources(&self) -> BTreeMap<String, &Source> {
+        shared_sources(
+            self.extensions
+                .iter()
+                .map(|extension| &extension.src)
+                .chain(self.grammars.iter().map(|grammar| &grammar.src)),
+        )
+    }
+
+    /// Moves grammars from per-extension ids to shared ids, see [`shared_grammar_id`].
+    ///
+    /// Extensions that used identical grammars end up sharing a single entry.
+    /// Old `{extension}_{grammar}` ids could collide, in which case only one extension owns the grammar file.
+    /// Any other extension is left pointing at a missing grammar, so it gets synced again.
+    pub fn migrate_grammar_ids(&mut self) {
+        let mut renamed = BTreeMap::new();
+        for grammar in &mut self.grammars {
+            let id = grammar.shared_id();
+            if grammar.id != id {
+                renamed.insert(std::mem::replace(&mut grammar.id, id.clone()), id);
+            }
+        }
+
+        if renamed.is_empty() {
+            return;
+        }
+
+        for extension in &mut self.extensions {
+            for id in &mut extension.grammars {
+                let Some(new_id) = renamed.get(id) else {
+                    continue;
+                };
+
+                let owned = self.grammars.iter().any(|grammar| {
+                    &grammar.id == new_id
+                        && (*id == grammar_id(&extension.name, &grammar.name)
+                            || *id == format!("{}_{}", extension.name, grammar.name))
+                });
+
+                if owned {
+                    tracing::debug!(old = id, new = new_id, "Migrating grammar id");
+                    id.clone_from(new_id);
+                }
+            }
+        }
+
+        self.grammars.sort_by(|a, b| a.id.cmp(&b.id));
+        self.grammars.dedup_by(|a, b| a.id == b.id);
+    }
+
+    /// Whether every grammar the extension references is present.
+    #[must_use]
+    pub fn has_grammars(&self, extension: &Extension) -> bool {
+        extension
+            .grammars
+            .iter()
+            .all(|id| self.grammars.iter().any(|grammar| &grammar.id == id))
+    }
+
+    /// Grammars that need `tree-sitter generate`, mapped to the `tree-sitter-cli` version they declare.
+    #[must_use]
+    pub fn needs_generate(&self) -> BTreeMap<String, Option<String>> {
+        self.grammars
+            .iter()
+            .filter(|grammar| grammar.needs_generate)
+            .map(|grammar| (grammar.id.clone(), grammar.cli_version.clone()))
+            .collect()
+    }
+
+    /// Grammars each extension declares without any of its languages using them, by name.
+    #[must_use]
+    pub fn unused_grammars(&self) -> BTreeMap<String, Vec<String>> {
+        self.extensions
+            .iter()
+            .filter_map(|extension| {
+                let languages = extension.languages.as_ref()?;
+                let unused: Vec<String> = self
+                    .grammars
+                    .iter()
+                    .filter(|grammar| extension.grammars.contains(&grammar.id))
+                    .filter(|grammar| !languages.contains_key(&grammar.name))
+                    .map(|grammar| grammar.name.clone())
+                    .collect();
+
+                (!unused.is_empty()).then(|| (extension.name.clone(), unused))
+            })
+            .collect()
+    }
+
+    /// Grammars each extension's languages use without the extension declaring them, by name.
+    #[must_use]
+    pub fn undeclared_grammars(&self) -> BTreeMap<String, Vec<String>> {
+        self.extensions
+            .iter()
+            .filter_map(|extension| {
+                let languages = extension.languages.as_ref()?;
+                let undeclared: Vec<String> = languages
+                    .keys()
+                    .filter(|name| {
+                        !self.grammars.iter().any(|grammar| {
+                            &grammar.name == *name && extension.grammars.contains(&grammar.id)
+                        })
+                    })
+                    .cloned()
+                    .collect();
+
+                (!undeclared.is_empty()).then(|| (extension.name.clone(), undeclared))
+            })
+            .collect()
+    }
+
+    /// Grammars with an ABI version outside `supported`, paired with each extension using them.
+    #[must_use]
+    pub fn unsupported_grammars(&self, supported: &RangeInclusive<u32>) -> Vec<(&str, &Grammar)> {
+        self.extensions
+            .iter()
+            .flat_map(|extension| {
+                self.grammars
+                    .iter()
+                    .filter(|grammar| extension.grammars.contains(&grammar.id))
+                    .filter(|grammar| {
+                        grammar
+                            .abi_version
+                            .is_some_and(|version| !supported.contains(&version))
+                    })
+                    .map(|grammar| (extension.name.as_str(), grammar))
+            })
+            .collect()
+    }
+
+    /// Finds a grammar that shares an id with an existing grammar built from a different source.
+    #[must_use]
+    pub fn grammar_conflict<'a>(&self, grammars: &'a [Grammar]) -> Option<&'a str> {
+        grammars
+            .iter()
+            .find(|grammar| {
+                self.grammars.iter().any(|existing| {
+                    existing.id == grammar.id
+                        && (existing.src != grammar.src
+                            || existing.grammar_root != grammar.grammar_root)
+                })
+            })
+            .map(|grammar| grammar.id.as_str())
+    }
+
+    /// Grammar ids referenced by the named extension alone, which can go once it's replaced.
+    #[must_use]
+    pub fn outdated_grammars(&self, name: &str) -> Vec<String> {
+        let Some(existing) = self
+            .extensions
+            .iter()
+            .find(|existing| existing.name == name)
+        else {
+            return vec![];
+        };
+
+        existing
+            .grammars
+            .iter()
+            .filter(|id| {
+                !self
+                    .extensions
+                    .iter()
+                    .any(|other| other.name != name && other.grammars.contains(id))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Adds an extension and its grammars, replacing any existing entry of the same name.
+    pub fn upsert(&mut self, extension: Extension, grammars: Vec<Grammar>) {
+        let outdated = self.outdated_grammars(&extension.name);
+
+        self.grammars
+            .retain(|grammar| !outdated.contains(&grammar.id));
+
+        self.extensions
+            .retain(|existing| existing.name != extension.name);
+
+        self.extensions.push(extension);
+
+        for grammar in grammars {
+            if !self
+                .grammars
+                .iter()
+                .any(|existing| existing.id == grammar.id)
+            {
+                self.grammars.push(grammar);
+            }
+        }
+    }
+
+    /// Removes grammars that no extension references.
+    pub fn prune_grammars(&mut self) {
+        let extensions = &self.extensions;
+        self.grammars.retain(|grammar| {
+            extensions
+                .iter()
+                .any(|extension| extension.grammars.contains(&grammar.id))
+        });
+    }
+
+    /// Maps the per-extension id of every grammar to its shared id.
+    #[must_use]
+    pub fn grammar_aliases(&self) -> BTreeMap<String, String> {
+        let mut aliases = BTreeMap::new();
+        for extension in &self.extensions {
+            for id in &extension.grammars {
+                if let Some(grammar) = self.grammars.iter().find(|grammar| &grammar.id == id) {
+                    aliases.insert(grammar_id(&extension.name, &grammar.name), id.clone());
+                }
+            }
+        }
+
+        aliases
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Extension {
     pub name: String,
+    /// The `id` from the extension manifest.
+    #[serde(default)]
+    pub id: Option<String>,
     pub version: String,
+    /// The revision the extension was synced at as given, before it was resolved to `src.rev`.
+    #[serde(default)]
+    pub rev: Option<String>,
+    /// The subdirectory the extension was synced from as given, unknown for extensions synced before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
     pub src: Source,
     #[serde(rename = "extensionRoot")]
     pub extension_root: Option<String>,
     pub grammars: Vec<String>,
+    /// Names of the extension's languages, keyed by the name of the grammar they use.
+    ///
+    /// Unknown for extensions synced before it was recorded.
+    #[serde(default)]
+    pub languages: Option<BTreeMap<String, Vec<String>>>,
+    /// Registry the extension was synced from.
+    #[serde(default)]
+    pub registry: Option<String>,
+    /// Registry commit the extension was last synced at.
+    #[serde(rename = "registryRev", default)]
+    pub registry_rev: Option<String>,
     #[serde(flatten)]
     pub kind: ExtensionKind,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(tag = "kind", rename_all = "lowercase")]
 pub enum ExtensionKind {
     Plain,
@@ -35,7 +278,7 @@ pub enum ExtensionKind {
     },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct CargoLock {
     #[serde(rename = "lockFile")]
     pub lock_file: PathBuf,
@@ -44,7 +287,7 @@ pub struct CargoLock {
 }
 
 #[allow(clippy::struct_excessive_bools)]
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Source {
     pub url: String,
     pub rev: String,
@@ -60,14 +303,202 @@ pub struct Source {
     pub deep_clone: bool,
     #[serde(rename = "leaveDotGit")]
     pub leave_dot_git: bool,
+    /// Directories the checkout is limited to, or everything if empty.
+    #[serde(rename = "sparseCheckout", default)]
+    pub sparse_checkout: Vec<String>,
+}
+
+/// Per-extension name of a grammar, like `{extension}__{grammar}`, kept as an alias of its shared id.
+///
+/// Extension names can't contain `__` or end with `_`, and grammar names can't contain `__` or start with `_`.
+/// So the first `__` always separates the two, and different pairs never share an id.
+#[must_use]
+pub fn grammar_id(extension: &str, grammar: &str) -> String {
+    format!("{extension}__{grammar}")
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Identifies a grammar by what it's built from, like `{grammar}-{hash}`.
+///
+/// Extensions using the same grammar at the same commit share a single entry.
+#[must_use]
+pub fn shared_grammar_id(name: &str, url: &str, rev: &str, grammar_root: Option<&str>) -> String {
+    let key = [url, rev, grammar_root.unwrap_or_default(), name].join("\0");
+    format!("{name}-{:016x}", stable_hash(key.as_bytes()))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Grammar {
     pub id: String,
     pub name: String,
+    /// The commit the grammar is built from.
     pub version: String,
+    /// The tag or branch from the manifest that resolved to `version`, if it wasn't a commit.
+    #[serde(rename = "ref", default)]
+    pub reference: Option<String>,
     pub src: Source,
     #[serde(rename = "grammarRoot")]
     pub grammar_root: Option<String>,
+    /// Whether `src/parser.c` exists, unknown for grammars synced before it was recorded.
+    #[serde(rename = "hasParser", default)]
+    pub has_parser: Option<bool>,
+    /// The `tree_sitter_*` function defined in `src/parser.c`.
+    #[serde(default)]
+    pub symbol: Option<String>,
+    /// The external scanner, if the grammar has one.
+    #[serde(default)]
+    pub scanner: Option<Scanner>,
+    /// Whether the parser has to be generated with `tree-sitter generate` before building.
+    #[serde(rename = "needsGenerate", default)]
+    pub needs_generate: bool,
+    /// The `tree-sitter-cli` version the grammar declares, if it needs generating.
+    #[serde(rename = "cliVersion", default)]
+    pub cli_version: Option<String>,
+    /// The tree-sitter language ABI version `parser.c` was generated for.
+    #[serde(rename = "abiVersion", default)]
+    pub abi_version: Option<u32>,
+    /// Details from the grammar's `tree-sitter.json`, if it has one.
+    #[serde(default)]
+    pub metadata: Option<GrammarMetadata>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GrammarMetadata {
+    pub version: Option<String>,
+    pub license: Option<String>,
+    pub description: Option<String>,
+    /// The editor scope, like `source.nix`.
+    pub scope: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Scanner {
+    /// Path relative to the grammar root, like `src/scanner.c`.
+    pub file: String,
+    pub language: ScannerLanguage,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScannerLanguage {
+    C,
+    Cpp,
+}
+
+impl Grammar {
+    #[must_use]
+    pub fn shared_id(&self) -> String {
+        shared_grammar_id(
+            &self.name,
+            &self.src.url,
+            &self.src.rev,
+            self.grammar_root.as_deref(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn source(url: &str) -> serde_json::Value {
+        json!({
+            "url": url,
+            "rev": "0123456789abcdef0123456789abcdef01234567",
+            "date": "",
+            "path": "",
+            "sha256": "",
+            "hash": "",
+            "fetchLFS": false,
+            "fetchSubmodules": false,
+            "deepClone": false,
+            "leaveDotGit": false,
+        })
+    }
+
+    fn extension(name: &str, grammars: &[&str]) -> Extension {
+        serde_json::from_value(json!({
+            "name": name,
+            "version": "0.1.0",
+            "src": source(&format!("https://github.com/example/{name}")),
+            "extensionRoot": null,
+            "grammars": grammars,
+            "kind": "plain",
+        }))
+        .unwrap()
+    }
+
+    fn grammar(id: &str, name: &str, url: &str) -> Grammar {
+        serde_json::from_value(json!({
+            "id": id,
+            "name": name,
+            "version": "0123456789abcdef0123456789abcdef01234567",
+            "src": source(url),
+            "grammarRoot": null,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn grammar_ids_are_unambiguous() {
+        assert_eq!(grammar_id("a", "b_c"), "a__b_c");
+        assert_ne!(grammar_id("a", "b_c"), grammar_id("a_b", "c"));
+    }
+
+    #[test]
+    fn migrates_grammar_ids_to_shared_ids() {
+        let url = "https://github.com/example/tree-sitter-nix";
+        let mut output = NixExtensions {
+            extensions: vec![extension("a", &["a_nix"]), extension("b", &["b__nix"])],
+            grammars: vec![grammar("a_nix", "nix", url), grammar("b__nix", "nix", url)],
+        };
+
+        output.migrate_grammar_ids();
+
+        let id = shared_grammar_id("nix", url, &output.grammars[0].src.rev, None);
+        assert_eq!(output.grammars.len(), 1);
+        assert_eq!(output.grammars[0].id, id);
+        assert_eq!(output.extensions[0].grammars, output.extensions[1].grammars);
+        assert_eq!(output.extensions[1].grammars, [id]);
+    }
+
+    #[test]
+    fn leaves_colliding_grammar_ids_to_their_owner() {
+        // `a_b` with grammar `c` and `a` with grammar `b_c` both used to get `a_b_c`.
+        let url = "https://github.com/example/tree-sitter-c";
+        let mut output = NixExtensions {
+            extensions: vec![extension("a", &["a_b_c"]), extension("a_b", &["a_b_c"])],
+            grammars: vec![grammar("a_b_c", "c", url)],
+        };
+
+        output.migrate_grammar_ids();
+
+        let id = output.grammars[0].id.clone();
+        assert_eq!(output.extensions[0].grammars, ["a_b_c"]);
+        assert!(!output.has_grammars(&output.extensions[0]));
+        assert_eq!(output.extensions[1].grammars, [id]);
+        assert!(output.has_grammars(&output.extensions[1]));
+    }
+
+    #[test]
+    fn finds_grammar_conflicts() {
+        let output = NixExtensions {
+            extensions: vec![],
+            grammars: vec![grammar("nix", "nix", "https://github.com/example/one")],
+        };
+
+        let same = [grammar("nix", "nix", "https://github.com/example/one")];
+        assert_eq!(output.grammar_conflict(&same), None);
+
+        let other_source = [grammar("nix", "nix", "https://github.com/example/two")];
+        assert_eq!(output.grammar_conflict(&other_source), Some("nix"));
+
+        let mut other_root = grammar("nix", "nix", "https://github.com/example/one");
+        other_root.grammar_root = Some("nix".to_owned());
+        assert_eq!(output.grammar_conflict(&[other_root]), Some("nix"));
+
+        let unrelated = [grammar("rust", "rust", "https://github.com/example/two")];
+        assert_eq!(output.grammar_conflict(&unrelated), None);
+    }
 }
@@ -0,0 +1,237 @@
+//! Local adjustments applied on top of the registry during `sync`.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Component, Path},
+};
+
+use serde::Deserialize;
+use smol::fs;
+
+use crate::registry::RegistryExtension;
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Override {
+    /// Leave the extension as it is, without syncing it.
+    #[serde(default)]
+    pub skip: bool,
+    /// Version of the extension at `rev`, which it's required with.
+    pub version: Option<String>,
+    /// Build the extension from this revision.
+    pub rev: Option<String>,
+    /// Fetch the extension from this repository.
+    pub repository: Option<String>,
+    /// Look for the extension in this subdirectory of the repository.
+    pub path: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Overrides {
+    entries: BTreeMap<String, Override>,
+}
+
+impl Overrides {
+    /// Reads and validates the overrides file, treating a missing file as empty.
+    pub async fn load(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let content = fs::read_to_string(path).await?;
+        let entries: BTreeMap<String, Override> = toml::from_str(&content)?;
+
+        let mut problems = vec![];
+        for (name, entry) in &entries {
+            problems.extend(
+                entry
+                    .validate()
+                    .into_iter()
+                    .map(|problem| format!("{name}: {problem}")),
+            );
+        }
+
+        if !problems.is_empty() {
+            for problem in &problems {
+                tracing::error!("{problem}");
+            }
+
+            anyhow::bail!("Invalid overrides in {}", path.display());
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// Applies every override to the registry extensions.
+    ///
+    /// Returns the names of extensions whose source was replaced.
+    /// Those should be compared by source rather than version to decide whether they changed.
+    pub fn apply(&self, extensions: &mut Vec<RegistryExtension>) -> BTreeSet<String> {
+        let mut overridden = BTreeSet::new();
+
+        for (name, entry) in &self.entries {
+            let Some(index) = extensions
+                .iter()
+                .position(|extension| &extension.name == name)
+            else {
+                tracing::warn!(name = name, "Override doesn't match any registry extension");
+                continue;
+            };
+
+            if entry.skip {
+                tracing::info!(name = name, "Skipping extension due to override");
+                extensions.remove(index);
+                continue;
+            }
+
+            let extension = &mut extensions[index];
+
+            if let Some(version) = &entry.version {
+                extension.version.clone_from(version);
+            }
+
+            if let Some(rev) = &entry.rev {
+                extension.rev.clone_from(rev);
+            }
+
+            if let Some(repository) = &entry.repository {
+                extension.repository.clone_from(repository);
+            }
+
+            if let Some(path) = &entry.path {
+                extension.path = Some(path.trim_start_matches("./").to_owned());
+            }
+
+            if entry.rev.is_some() || entry.repository.is_some() || entry.path.is_some() {
+                tracing::info!(name = name, "Applied source override");
+                overridden.insert(name.clone());
+            }
+        }
+
+        overridden
+    }
+}
+
+impl Override {
+    fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+
+        let changes_source = self.rev.is_some() || self.repository.is_some() || self.path.is_some();
+        if self.skip && (changes_source || self.version.is_some()) {
+            problems.push("'skip' can't be combined with other fields".to_owned());
+        }
+
+        if !self.skip && !changes_source && self.version.is_none() {
+            problems.push("override doesn't change anything".to_owned());
+        }
+
+        if let Some(version) = &self.version {
+            if version.trim().is_empty() {
+                problems.push("'version' is empty".to_owned());
+            }
+
+            // The registry only lists its latest version, so there's nothing to resolve it to.
+            if self.rev.is_none() {
+                problems.push("'version' requires the 'rev' it was released at".to_owned());
+            }
+        }
+
+        problems.extend(validate_source(
+            self.rev.as_deref(),
+            self.repository.as_deref(),
+            self.path.as_deref(),
+        ));
+
+        problems
+    }
+}
+
+/// Checks the parts of a git source that come from local configuration.
+pub fn validate_source(
+    rev: Option<&str>,
+    repository: Option<&str>,
+    path: Option<&str>,
+) -> Vec<String> {
+    let mut problems = vec![];
+
+    if let Some(rev) = rev
+        && (rev.is_empty() || rev.starts_with('-') || rev.contains(char::is_whitespace))
+    {
+        problems.push(format!("'rev' is not a valid revision: '{rev}'"));
+    }
+
+    if let Some(repository) = repository
+        && (repository.contains(char::is_whitespace)
+            || !(repository.contains("://") || repository.starts_with("git@")))
+    {
+        problems.push(format!("'repository' is not a git URL: '{repository}'"));
+    }
+
+    if let Some(path) = path {
+        let path = Path::new(path);
+        if path.as_os_str().is_empty()
+            || !path
+                .components()
+                .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            problems.push(format!(
+                "'path' must be a relative path inside the repository: '{}'",
+                path.display()
+            ));
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(source: &str) -> Vec<String> {
+        toml::from_str::<Override>(source).unwrap().validate()
+    }
+
+    #[test]
+    fn validates_overrides() {
+        assert!(problems("skip = true").is_empty());
+        assert!(problems(r#"rev = "v1.0.0""#).is_empty());
+        assert!(
+            problems(
+                r#"version = "1.0.0"
+rev = "v1.0.0""#
+            )
+            .is_empty()
+        );
+
+        assert!(!problems("").is_empty());
+        assert!(!problems(r#"version = "1.0.0""#).is_empty());
+        assert!(
+            !problems(
+                r#"skip = true
+rev = "v1.0.0""#
+            )
+            .is_empty()
+        );
+        assert!(!problems(r#"rev = "--all""#).is_empty());
+        assert!(!problems(r#"repository = "/tmp/zed-nix""#).is_empty());
+        assert!(!problems(r#"path = "../nix""#).is_empty());
+    }
+
+    #[test]
+    fn validates_sources() {
+        assert!(
+            validate_source(
+                Some("0123456789abcdef"),
+                Some("git@github.com:example/zed-nix.git"),
+                Some("./extensions/nix"),
+            )
+            .is_empty()
+        );
+        assert!(!validate_source(Some(""), None, None).is_empty());
+        assert!(!validate_source(Some("main branch"), None, None).is_empty());
+        assert!(!validate_source(None, Some("https://example.com/a b"), None).is_empty());
+        assert!(!validate_source(None, None, Some("")).is_empty());
+        assert!(!validate_source(None, None, Some("/nix")).is_empty());
+    }
+}
@@ -1,4 +1,20 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+    process::Command,
+};
+
+use anyhow::Context;
 use serde::Deserialize;
+use smol::fs;
+
+use crate::validate::validate_name;
+
+/// Name recorded for the official Zed extensions registry.
+pub const OFFICIAL_REGISTRY: &str = "official";
+
+/// Name recorded for extensions listed in the custom extensions file.
+pub const CUSTOM_REGISTRY: &str = "custom";
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct RegistryEntry {
@@ -14,4 +30,359 @@ pub struct RegistryExtension {
     pub repository: String,
     pub path: Option<String>,
     pub rev: String,
+    /// Name of the registry the extension was found in.
+    pub registry: String,
+    /// Commit of the registry the extension was found at.
+    pub registry_rev: Option<String>,
+}
+
+/// A git repository laid out like `zed-industries/extensions`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RegistrySource {
+    pub url: String,
+    /// Registries with a higher priority win when several list the same extension.
+    #[serde(default)]
+    pub priority: i64,
+}
+
+/// A point in a registry's history to sync from, instead of the tip of its default branch.
+#[derive(Debug, Clone)]
+pub enum RegistryRevision {
+    /// A commit, or anything else `git checkout` accepts.
+    Rev(String),
+    /// The last commit on the default branch before this time.
+    Date(jiff::Timestamp),
+}
+
+impl RegistryRevision {
+    /// Parses a date like `2025-01-31`, meaning the end of that day in UTC, or a full timestamp.
+    pub fn parse_date(value: &str) -> anyhow::Result<Self> {
+        if let Ok(timestamp) = value.parse::<jiff::Timestamp>() {
+            return Ok(Self::Date(timestamp));
+        }
+
+        let Ok(date) = value.parse::<jiff::civil::Date>() else {
+            anyhow::bail!("Invalid registry date: '{value}' (expected YYYY-MM-DD or RFC 3339)");
+        };
+
+        let end = date.tomorrow()?.to_zoned(jiff::tz::TimeZone::UTC)?;
+        Ok(Self::Date(end.timestamp()))
+    }
+}
+
+/// Everything listed by a single registry.
+#[derive(Debug, Clone)]
+pub struct Registry {
+    pub name: String,
+    pub url: String,
+    /// Commit of the registry that was read.
+    pub rev: String,
+    /// Commit date of `rev`.
+    pub date: String,
+    /// Every name in `extensions.toml`, including entries that couldn't be resolved.
+    pub names: BTreeSet<String>,
+    pub extensions: Vec<RegistryExtension>,
+}
+
+/// Reads the registries file, falling back to the official registry when missing.
+///
+/// Sources are returned in order of precedence.
+pub async fn load_registry_sources(path: &Path) -> anyhow::Result<Vec<(String, RegistrySource)>> {
+    if !path.exists() {
+        return Ok(vec![(
+            OFFICIAL_REGISTRY.to_owned(),
+            RegistrySource {
+                url: "https://github.com/zed-industries/extensions".to_owned(),
+                priority: 0,
+            },
+        )]);
+    }
+
+    let content = fs::read_to_string(path).await?;
+    let sources: BTreeMap<String, RegistrySource> = toml::from_str(&content)?;
+
+    for name in sources.keys() {
+        validate_name(name).context("Invalid registry name")?;
+    }
+
+    if sources.is_empty() {
+        anyhow::bail!("No registries configured in {}", path.display());
+    }
+
+    let mut sources: Vec<_> = sources.into_iter().collect();
+    sources.sort_by_key(|(_, source)| std::cmp::Reverse(source.priority));
+
+    for pair in sources.windows(2) {
+        if pair[0].1.priority == pair[1].1.priority {
+            anyhow::bail!(
+                "Registries '{}' and '{}' share priority {}",
+                pair[0].0,
+                pair[1].0,
+                pair[0].1.priority
+            );
+        }
+    }
+
+    Ok(sources)
+}
+
+/// Clones a registry into `dir` and resolves each entry to its submodule repository and revision.
+///
+/// Reads the tip of the default branch, unless a historical revision is given.
+pub async fn load_registry(
+    name: &str,
+    source: &RegistrySource,
+    dir: &Path,
+    at: Option<&RegistryRevision>,
+) -> anyhow::Result<Registry> {
+    let mode = if at.is_some() {
+        RegistryClone::Partial
+    } else {
+        RegistryClone::Shallow
+    };
+
+    clone_registry(name, source, dir, mode).await?;
+
+    if let Some(at) = at {
+        checkout_registry_revision(name, dir, at)?;
+    }
+
+    // Lookup registry commit
+    let commit = Command::new("git")
+        .current_dir(dir)
+        .args(["log", "-1", "--format=%H%n%cI"])
+        .output()?;
+
+    if !commit.status.success() {
+        anyhow::bail!("Failed to get registry commit");
+    }
+
+    let commit = String::from_utf8(commit.stdout)?;
+    let Some((rev, date)) = commit.trim().split_once('\n') else {
+        anyhow::bail!("Failed to parse registry commit");
+    };
+
+    tracing::info!(
+        registry = name,
+        rev = rev,
+        date = date,
+        "Found registry commit"
+    );
+
+    // Lookup registry extensions
+    let registry = dir.join("extensions.toml");
+    let registry = fs::read_to_string(registry).await?;
+    let registry: BTreeMap<String, RegistryEntry> = toml::from_str(&registry)?;
+
+    // Parse submodule revisions
+    let submodules = Command::new("git")
+        .current_dir(dir)
+        .args(["submodule", "status"])
+        .output()?;
+
+    if !submodules.status.success() {
+        anyhow::bail!("Failed to get submodule status");
+    }
+
+    let submodules = String::from_utf8(submodules.stdout)?.trim().to_owned();
+
+    let mut revisions: BTreeMap<String, String> = BTreeMap::new();
+    for line in submodules.lines() {
+        let parts: Vec<&str> = line.splitn(2, ' ').collect();
+
+        let revision = parts[0].trim_start_matches('-').to_owned();
+        let path = parts[1].to_owned();
+
+        revisions.insert(path, revision);
+    }
+
+    // Parse submodule repositories
+    let gitmodules = Command::new("git")
+        .current_dir(dir)
+        .args(["config", "--file", ".gitmodules", "--list"])
+        .output()?;
+
+    if !gitmodules.status.success() {
+        anyhow::bail!("Failed to get submodule repositories");
+    }
+
+    let gitmodules = String::from_utf8(gitmodules.stdout)?.trim().to_owned();
+
+    let mut repositories: BTreeMap<String, String> = BTreeMap::new();
+    for line in gitmodules.lines() {
+        let parts: Vec<&str> = line.splitn(2, '=').collect();
+
+        let path = parts[0]
+            .trim_start_matches("submodule.")
+            .trim_end_matches(".url")
+            .to_owned();
+
+        let repository = parts[1].trim_end_matches(".git").to_owned();
+        repositories.insert(path, repository);
+    }
+
+    // Merge details
+    let mut extensions: Vec<RegistryExtension> = vec![];
+    for (extension, entry) in &registry {
+        let Some(repository) = repositories.get(&entry.submodule) else {
+            tracing::warn!(
+                submodule = ?entry.submodule,
+                "Missing submodule repository"
+            );
+
+            continue;
+        };
+
+        let Some(revision) = revisions.get(&entry.submodule) else {
+            tracing::warn!(
+                submodule = ?entry.submodule,
+                "Missing submodule revision"
+            );
+
+            continue;
+        };
+
+        extensions.push(RegistryExtension {
+            name: extension.clone(),
+            version: entry.version.clone(),
+            repository: repository.clone(),
+            path: entry.path.clone(),
+            rev: revision.clone(),
+            registry: name.to_owned(),
+            registry_rev: Some(rev.to_owned()),
+        });
+    }
+
+    Ok(Registry {
+        name: name.to_owned(),
+        url: source.url.clone(),
+        rev: rev.to_owned(),
+        date: date.to_owned(),
+        names: registry.into_keys().collect(),
+        extensions,
+    })
+}
+
+/// How much of a registry to fetch when cloning it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistryClone {
+    /// Only the latest commit, checked out.
+    Shallow,
+    /// Every commit, with file contents fetched on demand, and nothing checked out.
+    Partial,
+    /// Every commit and file, with nothing checked out.
+    Full,
+}
+
+/// Clones a registry into `dir`.
+pub async fn clone_registry(
+    name: &str,
+    source: &RegistrySource,
+    dir: &Path,
+    mode: RegistryClone,
+) -> anyhow::Result<()> {
+    tracing::info!(
+        registry = name,
+        url = source.url,
+        "Cloning extensions registry"
+    );
+
+    if dir.exists() {
+        fs::remove_dir_all(dir).await?;
+    }
+
+    let flags: &[&str] = match mode {
+        RegistryClone::Shallow => &["--depth", "1"],
+        RegistryClone::Partial => &["--filter=blob:none", "--no-checkout"],
+        RegistryClone::Full => &["--no-checkout"],
+    };
+
+    let clone = Command::new("git")
+        .arg("clone")
+        .args(flags)
+        .args([&source.url, &*dir.to_string_lossy()])
+        .status()?;
+
+    if !clone.success() {
+        anyhow::bail!("Failed to clone extensions repository");
+    }
+
+    Ok(())
+}
+
+/// Checks out the registry commit matching `at`.
+fn checkout_registry_revision(name: &str, dir: &Path, at: &RegistryRevision) -> anyhow::Result<()> {
+    let rev = match at {
+        RegistryRevision::Rev(rev) => rev.clone(),
+        RegistryRevision::Date(date) => {
+            let before = format!("--before=@{}", date.as_second());
+            let rev_list = Command::new("git")
+                .current_dir(dir)
+                .args(["rev-list", "-1", "--first-parent", &before, "HEAD"])
+                .output()?;
+
+            if !rev_list.status.success() {
+                anyhow::bail!("Failed to find registry commit before {date}");
+            }
+
+            let rev = String::from_utf8(rev_list.stdout)?.trim().to_owned();
+            if rev.is_empty() {
+                anyhow::bail!("Registry '{name}' has no commits before {date}");
+            }
+
+            rev
+        }
+    };
+
+    tracing::info!(registry = name, rev = rev, "Checking out registry revision");
+
+    let checkout = Command::new("git")
+        .current_dir(dir)
+        .args(["checkout", "--quiet", "--detach", &rev])
+        .status()?;
+
+    if !checkout.success() {
+        anyhow::bail!("Failed to check out registry revision '{rev}'");
+    }
+
+    Ok(())
+}
+
+/// Merges registries given in order of precedence, keeping the first resolved entry for each name.
+///
+/// Returns the merged extensions, and every name listed by more than one registry.
+/// Collisions map to the registries involved, winner first.
+#[must_use]
+pub fn merge_registries(
+    registries: &[Registry],
+) -> (Vec<RegistryExtension>, BTreeMap<String, Vec<String>>) {
+    let mut owners: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for registry in registries {
+        for name in &registry.names {
+            owners
+                .entry(name.as_str())
+                .or_default()
+                .push(registry.name.clone());
+        }
+    }
+
+    // Entries that couldn't be resolved are missing, so the next registry listing the name is used.
+    let mut merged = BTreeSet::new();
+    let mut extensions = vec![];
+    for registry in registries {
+        for extension in &registry.extensions {
+            if merged.insert(extension.name.as_str()) {
+                extensions.push(extension.clone());
+            }
+        }
+    }
+
+    let collisions = owners
+        .into_iter()
+        .filter(|(_, owners)| owners.len() > 1)
+        .map(|(name, owners)| (name.to_owned(), owners))
+        .collect();
+
+    (extensions, collisions)
 }
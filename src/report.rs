@@ -0,0 +1,38 @@
+use std::{collections::BTreeMap, path::Path};
+
+use serde::{Deserialize, Serialize};
+use smol::fs;
+
+/// Summary of a sync, kept so the next run knows what was left unfinished.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SyncReport {
+    /// Extensions that were due for processing but never finished.
+    #[serde(default)]
+    pub pending: Vec<String>,
+    /// Extensions listed by more than one registry, mapped to those registries, winner first.
+    #[serde(default)]
+    pub collisions: BTreeMap<String, Vec<String>>,
+    /// Extensions that were rejected or failed to process, mapped to the reason.
+    #[serde(default)]
+    pub failures: BTreeMap<String, String>,
+    /// Grammars that need `tree-sitter generate`, mapped to the `tree-sitter-cli` version they declare.
+    #[serde(rename = "needsGenerate", default)]
+    pub needs_generate: BTreeMap<String, Option<String>>,
+    /// Extensions mapped to the grammars they declare that none of their languages use.
+    #[serde(rename = "unusedGrammars", default)]
+    pub unused_grammars: BTreeMap<String, Vec<String>>,
+    /// Extensions mapped to the grammars their languages use without declaring them.
+    #[serde(rename = "undeclaredGrammars", default)]
+    pub undeclared_grammars: BTreeMap<String, Vec<String>>,
+}
+
+impl SyncReport {
+    pub async fn load(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let content = fs::read_to_string(path).await?;
+        Ok(serde_json::from_str(&content)?)
+    }
+}
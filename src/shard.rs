@@ -0,0 +1,82 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// One slice of the registry, written as `<index>/<total>` with a 1-based index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Shard {
+    pub index: u32,
+    pub total: u32,
+}
+
+impl Shard {
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let Some((index, total)) = value.split_once('/') else {
+            anyhow::bail!("Invalid shard: '{value}' (expected index/total)");
+        };
+
+        let (Ok(index), Ok(total)) = (index.parse(), total.parse()) else {
+            anyhow::bail!("Invalid shard: '{value}' (expected index/total)");
+        };
+
+        if total == 0 || index == 0 || index > total {
+            anyhow::bail!("Invalid shard: '{value}' (index must be between 1 and total)");
+        }
+
+        Ok(Self { index, total })
+    }
+
+    /// Whether the extension belongs to this shard.
+    ///
+    /// Based on a hash of the name alone, so registry additions don't reshuffle other extensions.
+    #[must_use]
+    pub fn contains(&self, name: &str) -> bool {
+        stable_hash(name.as_bytes()) % u64::from(self.total) == u64::from(self.index - 1)
+    }
+}
+
+impl fmt::Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.total)
+    }
+}
+
+/// FNV-1a, which unlike `DefaultHasher` is guaranteed to stay the same across Rust releases.
+#[must_use]
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_shards() {
+        assert_eq!(Shard::parse("2/4").unwrap(), Shard { index: 2, total: 4 });
+        assert_eq!(Shard::parse("2/4").unwrap().to_string(), "2/4");
+        assert!(Shard::parse("0/4").is_err());
+        assert!(Shard::parse("5/4").is_err());
+        assert!(Shard::parse("1/0").is_err());
+        assert!(Shard::parse("1").is_err());
+    }
+
+    #[test]
+    fn puts_every_extension_in_one_shard() {
+        let shards: Vec<Shard> = (1..=4).map(|index| Shard { index, total: 4 }).collect();
+        for name in ["nix", "rust", "html", "toml", "catppuccin", "zig"] {
+            let containing = shards.iter().filter(|shard| shard.contains(name)).count();
+            assert_eq!(containing, 1, "{name}");
+        }
+
+        assert!(Shard { index: 1, total: 1 }.contains("nix"));
+    }
+
+    #[test]
+    fn hashes_stably() {
+        assert_eq!(stable_hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(stable_hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+}
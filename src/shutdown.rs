@@ -0,0 +1,73 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+use async_signal::{Signal, Signals};
+use smol::{channel, stream::StreamExt};
+
+use crate::sync::remove_scratch_dir;
+
+/// Tracks whether a sync has been asked to stop.
+///
+/// Once requested, no new extensions are started.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    sender: channel::Sender<()>,
+    receiver: channel::Receiver<()>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (sender, receiver) = channel::bounded(1);
+
+        Self {
+            requested: Arc::new(AtomicBool::new(false)),
+            sender,
+            receiver,
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn request(&self) {
+        if !self.requested.swap(true, Ordering::SeqCst) {
+            self.sender.close();
+        }
+    }
+
+    #[must_use]
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once a shutdown has been requested.
+    pub async fn wait(&self) {
+        // Closing the channel wakes every receiver.
+        self.receiver.recv().await.ok();
+    }
+}
+
+/// Requests a shutdown on the first SIGINT or SIGTERM, and exits immediately on the second.
+pub fn listen_for_signals(shutdown: Shutdown) -> anyhow::Result<()> {
+    let mut signals = Signals::new([Signal::Int, Signal::Term])?;
+
+    smol::spawn(async move {
+        while let Some(signal) = signals.next().await {
+            if shutdown.is_requested() {
+                tracing::error!(signal = ?signal, "Received second signal, exiting");
+
+                // Exiting skips destructors, including the one cleaning this up.
+                remove_scratch_dir();
+                std::process::exit(130);
+            }
+
+            tracing::warn!(signal = ?signal, "Received signal, shutting down");
+            shutdown.request();
+        }
+    })
+    .detach();
+
+    Ok(())
+}
@@ -0,0 +1,281 @@
+//! Sources shared between extensions and grammars, stored once each in `generated/sources/`.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use smol::lock::{Mutex, OnceCell};
+
+use crate::{output::Source, registry::RegistryExtension, shard::stable_hash, sync::scratch_dir};
+
+/// Key of a source in the shared table, like `tree-sitter-nix-0123456789abcdef`.
+///
+/// Covers everything that changes what gets fetched, so sources with different sparse checkouts never replace each other.
+/// Full checkouts leave the sparse checkout out, keeping the keys they had before it was covered.
+#[must_use]
+pub fn source_key(
+    url: &str,
+    rev: &str,
+    fetch_lfs: bool,
+    fetch_submodules: bool,
+    sparse_checkout: &[String],
+) -> String {
+    let mut options = format!("lfs={fetch_lfs},submodules={fetch_submodules}");
+    if !sparse_checkout.is_empty() {
+        options.push_str(",sparse=");
+        options.push_str(&sparse_checkout.join("\n"));
+    }
+    let key = [url, rev, &options].join("\0");
+
+    format!(
+        "{}-{:016x}",
+        repository_name(url),
+        stable_hash(key.as_bytes())
+    )
+}
+
+/// Name of the repository at `url`, for readable keys and directories.
+fn repository_name(url: &str) -> String {
+    let repository: String = url
+        .trim_end_matches('/')
+        .trim_end_matches(".git")
+        .rsplit(['/', ':'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .map(|char| {
+            if char.is_ascii_alphanumeric() || char == '-' || char == '_' {
+                char
+            } else {
+                '-'
+            }
+        })
+        .take(64)
+        .collect();
+
+    let repository = repository.trim_start_matches(['-', '_']);
+    if repository.is_empty() {
+        "source".to_owned()
+    } else {
+        repository.to_owned()
+    }
+}
+
+impl Source {
+    #[must_use]
+    pub fn key(&self) -> String {
+        source_key(
+            &self.url,
+            &self.rev,
+            self.fetch_lfs,
+            self.fetch_submodules,
+            &self.sparse_checkout,
+        )
+    }
+
+    /// Whether this source holds everything a checkout limited to `sparse_checkout` would.
+    #[must_use]
+    pub fn covers(&self, sparse_checkout: &[String]) -> bool {
+        self.sparse_checkout.is_empty()
+            || (!sparse_checkout.is_empty()
+                && sparse_checkout
+                    .iter()
+                    .all(|path| self.sparse_checkout.contains(path)))
+    }
+}
+
+/// Picks the source stored under each key, preferring those that cover the others.
+#[must_use]
+pub fn shared_sources<'a>(
+    sources: impl IntoIterator<Item = &'a Source>,
+) -> BTreeMap<String, &'a Source> {
+    let mut shared: BTreeMap<String, &Source> = BTreeMap::new();
+    for src in sources {
+        let key = src.key();
+        match shared.get(&key) {
+            Some(existing) if !src.covers(&existing.sparse_checkout) || *existing == src => (),
+            _ => {
+                shared.insert(key, src);
+            }
+        }
+    }
+
+    shared
+}
+
+/// A clone of a repository, shared by every checkout made from it and removed once nothing uses it.
+#[derive(Debug)]
+pub struct RepositoryClone {
+    pub dir: PathBuf,
+    /// Set once the repository has been cloned.
+    pub cloned: OnceCell<()>,
+    /// Held while fetching and adding worktrees, which git doesn't allow concurrently.
+    pub lock: Mutex<()>,
+}
+
+impl Drop for RepositoryClone {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_dir_all(&self.dir) {
+            tracing::debug!(dir = ?self.dir, err = %err, "Failed to remove clone");
+        }
+    }
+}
+
+/// A source being fetched, or the widest one fetched so far.
+type SourceEntry = Arc<Mutex<Option<Source>>>;
+
+/// Subdirectories of extensions, keyed by repository and revision.
+type ExtensionPaths = BTreeMap<(String, String), BTreeSet<Option<String>>>;
+
+/// Sources pre-fetched and repositories cloned during a run, so each one is only fetched once.
+#[derive(Debug, Clone, Default)]
+pub struct SourceCache {
+    entries: Arc<Mutex<BTreeMap<String, SourceEntry>>>,
+    clones: Arc<Mutex<BTreeMap<String, Weak<RepositoryClone>>>>,
+    /// Subdirectories of the extensions at each repository revision, `None` being the root.
+    paths: Arc<ExtensionPaths>,
+}
+
+impl SourceCache {
+    /// Creates a cache for syncing `extensions`, including those handled by other shards.
+    #[must_use]
+    pub fn new(extensions: &[RegistryExtension]) -> Self {
+        let mut paths = ExtensionPaths::new();
+        for extension in extensions {
+            paths
+                .entry((extension.repository.clone(), extension.rev.clone()))
+                .or_default()
+                .insert(extension.path.clone());
+        }
+
+        Self {
+            paths: Arc::new(paths),
+            ..Self::default()
+        }
+    }
+
+    /// Subdirectories of every known extension at `rev` of `repository`.
+    #[must_use]
+    pub fn paths(&self, repository: &str, rev: &str) -> BTreeSet<Option<String>> {
+        self.paths
+            .get(&(repository.to_owned(), rev.to_owned()))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Returns the source for `key`, running `fetch` unless an earlier call fetched one covering `sparse_checkout`.
+    ///
+    /// Concurrent calls for the same key wait for a single fetch.
+    pub async fn get_or_fetch(
+        &self,
+        key: String,
+        sparse_checkout: &[String],
+        fetch: impl Future<Output = anyhow::Result<Source>>,
+    ) -> anyhow::Result<Source> {
+        let entry = Arc::clone(self.entries.lock().await.entry(key).or_default());
+        let mut cached = entry.lock().await;
+
+        if let Some(src) = cached.as_ref().filter(|src| src.covers(sparse_checkout)) {
+            return Ok(src.clone());
+        }
+
+        let src = fetch.await?;
+        if cached
+            .as_ref()
+            .is_none_or(|existing| src.covers(&existing.sparse_checkout))
+        {
+            *cached = Some(src.clone());
+        }
+
+        drop(cached);
+        Ok(src)
+    }
+
+    /// Returns the clone of `url` in use, or a new one to clone it into.
+    pub async fn clone_of(&self, url: &str) -> Arc<RepositoryClone> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let mut clones = self.clones.lock().await;
+        if let Some(clone) = clones.get(url).and_then(Weak::upgrade) {
+            return clone;
+        }
+
+        // Numbered, as a clone that just went out of use may still be getting removed.
+        let index = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let name = format!(
+            "{}-{:016x}-{index}",
+            repository_name(url),
+            stable_hash(url.as_bytes())
+        );
+
+        let clone = Arc::new(RepositoryClone {
+            dir: scratch_dir().join("clones").join(name),
+            cloned: OnceCell::new(),
+            lock: Mutex::new(()),
+        });
+
+        clones.insert(url.to_owned(), Arc::downgrade(&clone));
+        clone
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(sparse_checkout: &[&str]) -> Source {
+        Source {
+            url: "https://github.com/zed-industries/zed".to_owned(),
+            rev: "0123456789abcdef".to_owned(),
+            date: String::new(),
+            path: String::new(),
+            sha256: String::new(),
+            hash: format!("sha256-{}", sparse_checkout.join(",")),
+            fetch_lfs: false,
+            fetch_submodules: false,
+            deep_clone: false,
+            leave_dot_git: false,
+            sparse_checkout: sparse_checkout.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn keys_readable_by_repository() {
+        assert!(source(&[]).key().starts_with("zed-"));
+        assert!(
+            source_key("git@github.com:foo/bar.git", "rev", false, false, &[]).starts_with("bar-")
+        );
+        assert!(
+            source_key("https://example.com/", "rev", false, false, &[])
+                .starts_with("example-com-")
+        );
+        assert!(
+            source_key("https://example.com/.../", "rev", false, false, &[]).starts_with("source-")
+        );
+    }
+
+    #[test]
+    fn covers_sparse_checkouts() {
+        assert!(source(&[]).covers(&[]));
+        assert!(source(&[]).covers(&["one".to_owned()]));
+        assert!(source(&["one", "two"]).covers(&["one".to_owned()]));
+        assert!(!source(&["one"]).covers(&["one".to_owned(), "two".to_owned()]));
+        assert!(!source(&["one"]).covers(&[]));
+    }
+
+    #[test]
+    fn keeps_sources_with_different_sparse_checkouts_apart() {
+        let one = source(&["html", "shared"]);
+        let two = source(&["proto", "shared"]);
+        assert_ne!(one.key(), two.key());
+
+        let shared = shared_sources([&one, &two]);
+        assert_eq!(shared.get(&one.key()), Some(&&one));
+        assert_eq!(shared.get(&two.key()), Some(&&two));
+    }
+}
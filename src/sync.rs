@@ -1,28 +1,80 @@
-use std::{env::temp_dir, path::Path};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env::temp_dir,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use grammar::process_grammars;
-use rust::process_rust_extension;
+use language::read_languages;
+use rust::{find_path_dependencies, find_workspace_root, process_rust_extension};
 use smol::{fs, process::Command};
 
 use crate::{
+    generated::GeneratedDir,
     manifest::ExtensionManifest,
     output::{Extension, ExtensionKind, Grammar, Source},
     registry::RegistryExtension,
+    source::{RepositoryClone, SourceCache, source_key},
+    validate::{validate_name, validate_version},
 };
 
 mod grammar;
+mod language;
 mod rust;
+mod tree_sitter;
+
+/// Directory holding every temporary checkout made during a sync.
+///
+/// Named after the process, so concurrent runs, like shards on the same machine, each have their own.
+#[must_use]
+pub fn scratch_dir() -> PathBuf {
+    temp_dir().join(format!("nix-zed-extensions-{}", std::process::id()))
+}
+
+/// Directory for the checkout of `name` in the scratch directory, apart from shared clones and other kinds of checkouts.
+#[must_use]
+pub fn checkout_dir(kind: &str, name: &str) -> PathBuf {
+    scratch_dir().join("checkouts").join(kind).join(name)
+}
+
+/// Removes the scratch directory when dropped, so runs that fail don't leave checkouts behind.
+pub struct ScratchDir;
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        remove_scratch_dir();
+    }
+}
+
+/// Removes the scratch directory and everything in it.
+pub fn remove_scratch_dir() {
+    let dir = scratch_dir();
+    if let Err(err) = std::fs::remove_dir_all(&dir)
+        && err.kind() != std::io::ErrorKind::NotFound
+    {
+        tracing::warn!(dir = %dir.display(), err = %err, "Failed to remove scratch directory");
+    }
+}
 
 pub async fn process_extension(
     extension: RegistryExtension,
+    output: &GeneratedDir,
+    cache: &SourceCache,
 ) -> anyhow::Result<Option<(Extension, Vec<Grammar>)>> {
     tracing::info!("Synching extension");
 
     let name = extension.name.clone();
     let repo = extension.repository.clone();
 
-    let tmp_repo = temp_dir().join(&name);
-    checkout_git_repo(&repo, &extension.rev, &tmp_repo).await?;
+    let checkout = checkout_git_repo(
+        cache,
+        &repo,
+        &extension.rev,
+        &checkout_dir("extensions", &name),
+    )
+    .await?;
+    let tmp_repo = &checkout.dir;
 
     let extension_dir = if let Some(path) = &extension.path {
         tmp_repo.join(path)
@@ -33,7 +85,6 @@ pub async fn process_extension(
     let manifest = extension_dir.join("extension.toml");
     if !manifest.exists() {
         tracing::error!("Missing extension.toml");
-        fs::remove_dir_all(&tmp_repo).await?;
         return Ok(None);
     }
 
@@ -41,40 +92,160 @@ pub async fn process_extension(
     let manifest = fs::read_to_string(manifest).await?;
     let manifest: ExtensionManifest = toml::from_str(&manifest)?;
 
-    let src = prefetch_git_repo(&repo, &extension.rev, false).await?;
-    let grammars = process_grammars(manifest.grammars, &name).await?;
+    validate_name(&manifest.id)?;
+    validate_version(&manifest.version)?;
+
+    let languages = read_languages(&extension_dir, &manifest.languages).await?;
+    for grammar in manifest.grammars.keys() {
+        if !languages.contains_key(grammar) {
+            tracing::warn!(grammar = grammar, "Grammar is not used by any language");
+        }
+    }
+
+    for (grammar, languages) in &languages {
+        if !manifest.grammars.contains_key(grammar) {
+            tracing::warn!(
+                grammar = grammar,
+                languages = ?languages,
+                "Languages use an undeclared grammar"
+            );
+        }
+    }
+
+    let grammars = process_grammars(manifest.grammars, &name, cache).await?;
 
     let (kind, extension_root) = if extension_dir.join("Cargo.toml").exists() {
-        process_rust_extension(&extension, &extension_dir, &name).await?
+        process_rust_extension(&extension, &extension_dir, &name, output).await?
     } else {
         (ExtensionKind::Plain, extension.path.clone())
     };
 
-    fs::remove_dir_all(&tmp_repo).await?;
+    // Extensions at the same revision share a source, so it covers all of them.
+    let mut paths = cache.paths(&repo, &extension.rev);
+    paths.insert(extension.path.clone());
+
+    let mut fetch = detect_fetch_options(tmp_repo, extension.path.as_deref()).await?;
+    fetch.sparse_checkout = sparse_checkout(tmp_repo, &paths).await?;
+
+    let src = prefetch_source(cache, &repo, &checkout.resolved.commit, &fetch).await?;
 
     Ok(Some((
         Extension {
             name,
+            id: Some(manifest.id),
             version: manifest.version,
+            rev: Some(extension.rev.clone()),
+            path: extension.path.clone(),
             src,
             extension_root,
             grammars: grammars.ids,
+            languages: Some(languages),
+            registry: Some(extension.registry.clone()),
+            registry_rev: extension.registry_rev.clone(),
             kind,
         },
         grammars.grammars,
     )))
 }
 
-async fn checkout_git_repo(repo: &str, rev: &str, dest: &Path) -> anyhow::Result<()> {
+/// A revision resolved to the commit it points at.
+pub struct ResolvedRev {
+    pub commit: String,
+    /// The ref that was resolved, like `refs/tags/v1.0`, unless the revision was already a commit.
+    pub reference: Option<String>,
+}
+
+/// A worktree of a shared clone, removed when dropped.
+pub struct Checkout {
+    pub dir: PathBuf,
+    pub resolved: ResolvedRev,
+    _clone: Arc<RepositoryClone>,
+}
+
+impl Drop for Checkout {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_dir_all(&self.dir) {
+            tracing::debug!(dir = ?self.dir, err = %err, "Failed to remove checkout");
+        }
+    }
+}
+
+/// Checks out `rev` of `repo` into `dest`, where `rev` can be a commit, tag or branch.
+///
+/// The repository is cloned once through `cache`, and shared by every checkout made while it's in use.
+async fn checkout_git_repo(
+    cache: &SourceCache,
+    repo: &str,
+    rev: &str,
+    dest: &Path,
+) -> anyhow::Result<Checkout> {
     tracing::info!("Checking out repository");
 
+    let clone = cache.clone_of(repo).await;
+    clone
+        .cloned
+        .get_or_try_init(|| clone_git_repo(repo, &clone.dir))
+        .await?;
+
+    let _lock = clone.lock.lock().await;
+    let resolved = resolve_git_rev(repo, rev, &clone.dir).await?;
+
+    tracing::info!(commit = resolved.commit, "Fetching revision");
+    let fetch = Command::new("git")
+        .kill_on_drop(true)
+        .args(["fetch", "origin", &resolved.commit])
+        .current_dir(&clone.dir)
+        .output()
+        .await?;
+
+    if !fetch.status.success() {
+        anyhow::bail!("Failed to fetch revision");
+    }
+
+    if dest.exists() {
+        fs::remove_dir_all(dest).await?;
+    }
+
+    // Forget worktrees removed since, which would otherwise block reusing their path.
+    Command::new("git")
+        .kill_on_drop(true)
+        .args(["worktree", "prune"])
+        .current_dir(&clone.dir)
+        .output()
+        .await?;
+
+    tracing::info!("Checking out revision");
+    let checkout = Command::new("git")
+        .kill_on_drop(true)
+        .args(["worktree", "add", "--detach"])
+        .arg(dest)
+        .arg(&resolved.commit)
+        .current_dir(&clone.dir)
+        .output()
+        .await?;
+
+    if !checkout.status.success() {
+        anyhow::bail!("Failed to checkout revision");
+    }
+
+    Ok(Checkout {
+        dir: dest.to_path_buf(),
+        resolved,
+        _clone: Arc::clone(&clone),
+    })
+}
+
+/// Clones `repo` into `dest`, without checking anything out.
+async fn clone_git_repo(repo: &str, dest: &Path) -> anyhow::Result<()> {
     if dest.exists() {
         fs::remove_dir_all(dest).await?;
     }
 
     tracing::info!("Cloning repository");
     let clone = Command::new("git")
-        .args(["clone", repo, &dest.to_string_lossy()])
+        .kill_on_drop(true)
+        .args(["clone", "--no-checkout", "--", repo])
+        .arg(dest)
         .output()
         .await?;
 
@@ -82,53 +253,286 @@ async fn checkout_git_repo(repo: &str, rev: &str, dest: &Path) -> anyhow::Result
         anyhow::bail!("Failed to clone repository");
     }
 
-    tracing::info!("Fetching revision");
-    let fetch = Command::new("git")
-        .args(["fetch", "origin", rev])
-        .current_dir(dest)
+    Ok(())
+}
+
+fn is_commit_sha(rev: &str) -> bool {
+    rev.len() == 40 && rev.chars().all(|char| char.is_ascii_hexdigit())
+}
+
+/// Resolves `rev` like `git ls-remote` would, preferring tags over branches.
+///
+/// Abbreviated commits can't be listed remotely, so they are looked up in the clone at `dest`.
+async fn resolve_git_rev(repo: &str, rev: &str, dest: &Path) -> anyhow::Result<ResolvedRev> {
+    if is_commit_sha(rev) {
+        return Ok(ResolvedRev {
+            commit: rev.to_ascii_lowercase(),
+            reference: None,
+        });
+    }
+
+    if let Some(resolved) = resolve_remote_ref(repo, rev).await? {
+        if let Some(reference) = &resolved.reference
+            && (reference == "HEAD" || reference.starts_with("refs/heads/"))
+        {
+            tracing::warn!(
+                rev = rev,
+                commit = resolved.commit,
+                "Revision is a branch and can move, pin a commit or tag instead"
+            );
+        }
+
+        return Ok(resolved);
+    }
+
+    if (4..40).contains(&rev.len()) && rev.chars().all(|char| char.is_ascii_hexdigit()) {
+        tracing::debug!("Resolving abbreviated commit");
+        let rev_parse = Command::new("git")
+            .kill_on_drop(true)
+            .args([
+                "rev-parse",
+                "--verify",
+                "--quiet",
+                &format!("{rev}^{{commit}}"),
+            ])
+            .current_dir(dest)
+            .output()
+            .await?;
+
+        if rev_parse.status.success() {
+            let commit = str::from_utf8(&rev_parse.stdout)?.trim().to_owned();
+            return Ok(ResolvedRev {
+                commit,
+                reference: Some(rev.to_owned()),
+            });
+        }
+    }
+
+    anyhow::bail!("Failed to resolve revision: '{rev}'")
+}
+
+/// Resolves `rev` to the commit a tag or branch of `repo` points at, preferring tags over branches.
+///
+/// Returns `None` for revisions that aren't refs, like commits.
+pub async fn resolve_remote_ref(repo: &str, rev: &str) -> anyhow::Result<Option<ResolvedRev>> {
+    if rev.starts_with('-') {
+        anyhow::bail!("Invalid revision: '{rev}'");
+    }
+
+    // Annotated tags are listed twice, the peeled `^{}` entry being the tagged commit.
+    let candidates = if rev.starts_with("refs/") || rev == "HEAD" {
+        vec![format!("{rev}^{{}}"), rev.to_owned()]
+    } else {
+        vec![
+            format!("refs/tags/{rev}^{{}}"),
+            format!("refs/tags/{rev}"),
+            format!("refs/heads/{rev}"),
+        ]
+    };
+
+    tracing::debug!("Listing remote refs");
+    let ls_remote = Command::new("git")
+        .kill_on_drop(true)
+        .arg("ls-remote")
+        .arg("--")
+        .arg(repo)
+        .args(&candidates)
         .output()
         .await?;
 
-    if !fetch.status.success() {
-        anyhow::bail!("Failed to fetch revision");
+    if !ls_remote.status.success() {
+        anyhow::bail!("Failed to list remote refs");
     }
 
-    tracing::info!("Checking out revision");
-    let checkout = Command::new("git")
-        .args(["checkout", rev])
-        .current_dir(dest)
+    let refs: BTreeMap<&str, &str> = str::from_utf8(&ls_remote.stdout)?
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(commit, reference)| (reference, commit))
+        .collect();
+
+    Ok(candidates
+        .iter()
+        .find_map(|candidate| refs.get_key_value(candidate.as_str()))
+        .map(|(reference, commit)| ResolvedRev {
+            commit: (*commit).to_owned(),
+            reference: Some(reference.trim_end_matches("^{}").to_owned()),
+        }))
+}
+
+/// Extra content to fetch along with a repository, or what to limit it to.
+#[derive(Debug, Default, Clone)]
+struct FetchOptions {
+    lfs: bool,
+    submodules: bool,
+    /// Directories to check out, or everything if empty.
+    sparse_checkout: Vec<String>,
+}
+
+/// Checks whether the extension at `path` within the checkout at `dir` needs Git LFS files or submodules.
+async fn detect_fetch_options(dir: &Path, path: Option<&str>) -> anyhow::Result<FetchOptions> {
+    let lfs = uses_lfs(dir, path).await?;
+
+    // Only submodules the extension can see matter.
+    let prefix = path.map(|path| path.trim_start_matches("./").trim_end_matches('/'));
+    let submodules = submodule_paths(dir).await?.iter().any(|submodule| {
+        prefix.is_none_or(|prefix| {
+            submodule == prefix
+                || submodule.starts_with(&format!("{prefix}/"))
+                || prefix.starts_with(&format!("{submodule}/"))
+        })
+    });
+
+    if lfs || submodules {
+        tracing::info!(
+            lfs = lfs,
+            submodules = submodules,
+            "Source needs extra content"
+        );
+    }
+
+    Ok(FetchOptions {
+        lfs,
+        submodules,
+        sparse_checkout: vec![],
+    })
+}
+
+/// Whether any file under `path` within the checkout at `dir` is stored in Git LFS.
+///
+/// Git applies every `.gitattributes` file itself, from the repository root down to nested ones.
+async fn uses_lfs(dir: &Path, path: Option<&str>) -> anyhow::Result<bool> {
+    let pathspec = format!(":(attr:filter=lfs){}", path.unwrap_or("."));
+    let files = Command::new("git")
+        .kill_on_drop(true)
+        .args(["ls-files", "-z", "--", &pathspec])
+        .current_dir(dir)
         .output()
         .await?;
 
-    if !checkout.status.success() {
-        anyhow::bail!("Failed to checkout revision");
+    if !files.status.success() {
+        anyhow::bail!("Failed to list Git LFS files");
     }
 
-    Ok(())
+    Ok(!files.stdout.is_empty())
+}
+
+/// Lists the submodule paths in `.gitmodules` of the checkout at `dir`.
+async fn submodule_paths(dir: &Path) -> anyhow::Result<Vec<String>> {
+    if !dir.join(".gitmodules").exists() {
+        return Ok(vec![]);
+    }
+
+    let paths = Command::new("git")
+        .kill_on_drop(true)
+        .args([
+            "config",
+            "--file",
+            ".gitmodules",
+            "--get-regexp",
+            r"^submodule\..*\.path$",
+        ])
+        .current_dir(dir)
+        .output()
+        .await?;
+
+    // Exits with 1 when nothing matches.
+    match paths.status.code() {
+        Some(0) => (),
+        Some(1) => return Ok(vec![]),
+        _ => anyhow::bail!("Failed to read .gitmodules"),
+    }
+
+    // Lines like `submodule.<name>.path <path>`
+    Ok(str::from_utf8(&paths.stdout)?
+        .lines()
+        .filter_map(|line| line.split_once(' ').map(|(_, path)| path.trim().to_owned()))
+        .collect())
 }
 
+/// Directories covering every extension at `paths` within the checkout at `dir`, or none for the whole repository.
+///
+/// Standalone crates also need the directories of their `path` dependencies.
+/// Workspace members fetch everything, since Cargo needs every other member to load the workspace.
+async fn sparse_checkout(
+    dir: &Path,
+    paths: &BTreeSet<Option<String>>,
+) -> anyhow::Result<Vec<String>> {
+    let mut roots = BTreeSet::new();
+    for path in paths {
+        let Some(path) = path
+            .as_deref()
+            .map(|path| path.trim_start_matches("./").trim_end_matches('/'))
+            .filter(|path| !path.is_empty() && *path != ".")
+        else {
+            return Ok(vec![]);
+        };
+
+        let extension_dir = dir.join(path);
+        if extension_dir.join("Cargo.toml").exists()
+            && find_workspace_root(&extension_dir).await?.is_some()
+        {
+            return Ok(vec![]);
+        }
+
+        let dependencies = find_path_dependencies(dir, path).await?;
+        if dependencies.contains("") {
+            return Ok(vec![]);
+        }
+
+        roots.extend(dependencies);
+        roots.insert(path.to_owned());
+    }
+
+    Ok(roots.into_iter().collect())
+}
+
+/// Pre-fetches `rev` through `cache`, unless an earlier extension or grammar already fetched a source covering it.
+async fn prefetch_source(
+    cache: &SourceCache,
+    repo: &str,
+    rev: &str,
+    options: &FetchOptions,
+) -> anyhow::Result<Source> {
+    let key = source_key(
+        repo,
+        rev,
+        options.lfs,
+        options.submodules,
+        &options.sparse_checkout,
+    );
+
+    cache
+        .get_or_fetch(
+            key,
+            &options.sparse_checkout,
+            prefetch_git_repo(repo, rev, options),
+        )
+        .await
+}
+
+/// Pre-fetches `rev`, which must be a full commit.
 async fn prefetch_git_repo(
     repo: &str,
     rev: &str,
-    fetch_submodules: bool,
+    options: &FetchOptions,
 ) -> anyhow::Result<Source> {
     tracing::info!("Pre-fetching git source");
 
-    let rev = if (rev.len() == 40 && rev.chars().all(|char| char.is_ascii_hexdigit()))
-        || rev.starts_with("refs/")
-    {
-        rev.to_owned()
-    } else {
-        format!("refs/heads/{rev}")
-    };
-
-    let mut args = vec!["--url", repo, "--rev", &rev];
-    if fetch_submodules {
+    let sparse_checkout = options.sparse_checkout.join("\n");
+    let mut args = vec!["--url", repo, "--rev", rev];
+    if !sparse_checkout.is_empty() {
+        args.extend(["--sparse-checkout", &sparse_checkout]);
+    }
+    if options.lfs {
+        args.push("--fetch-lfs");
+    }
+    if options.submodules {
         args.push("--fetch-submodules");
     }
     args.push("--quiet");
 
     let prefetch = Command::new("nix-prefetch-git")
+        .kill_on_drop(true)
         .args(&args)
         .output()
         .await?;
@@ -137,7 +541,8 @@ async fn prefetch_git_repo(
         anyhow::bail!("Failed to pre-fetch repository");
     }
 
-    let src: Source = serde_json::from_slice(&prefetch.stdout)?;
+    let mut src: Source = serde_json::from_slice(&prefetch.stdout)?;
+    src.sparse_checkout.clone_from(&options.sparse_checkout);
     tracing::info!(src = ?src, "Pre-fetched git hash");
 
     Ok(src)
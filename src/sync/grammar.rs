@@ -1,11 +1,18 @@
-use std::{collections::BTreeMap, env::temp_dir};
+use std::collections::BTreeMap;
 
 use futures_util::stream::FuturesUnordered;
-use smol::{fs, stream::StreamExt};
+use smol::stream::StreamExt;
 use tracing::Instrument;
 
-use super::{checkout_git_repo, prefetch_git_repo};
-use crate::{manifest::GrammarManifestEntry, output::Grammar};
+use super::{
+    FetchOptions, checkout_dir, checkout_git_repo, prefetch_source, tree_sitter::inspect_grammar,
+};
+use crate::{
+    manifest::GrammarManifestEntry,
+    output::{Grammar, grammar_id, shared_grammar_id},
+    source::SourceCache,
+    validate::{validate_grammar_entry, validate_grammar_name, validate_name},
+};
 
 pub struct ProcessedGrammars {
     pub grammars: Vec<Grammar>,
@@ -15,10 +22,12 @@ pub struct ProcessedGrammars {
 pub async fn process_grammars(
     grammars: BTreeMap<String, GrammarManifestEntry>,
     name: &str,
+    cache: &SourceCache,
 ) -> anyhow::Result<ProcessedGrammars> {
     let mut futures = FuturesUnordered::new();
     for (grammar_name, grammar) in grammars {
         let name = name.to_owned();
+        let cache = cache.clone();
 
         let span = tracing::info_span!(
             "process_grammar",
@@ -29,7 +38,7 @@ pub async fn process_grammars(
         );
 
         let future = async move {
-            process_grammar(grammar_name, grammar, name)
+            process_grammar(grammar_name, grammar, name, &cache)
                 .instrument(span)
                 .await
         };
@@ -68,27 +77,76 @@ async fn process_grammar(
     name: String,
     grammar: GrammarManifestEntry,
     extension: String,
+    cache: &SourceCache,
 ) -> anyhow::Result<Option<Grammar>> {
-    let id = format!("{extension}_{name}");
-    let tmp_repo = temp_dir().join(&id);
+    validate_grammar_name(&name)?;
+    validate_grammar_entry(&grammar)?;
 
-    let repo = grammar.repository.clone();
-    let rev = grammar.rev.clone();
-    checkout_git_repo(&repo, &rev, &tmp_repo).await?;
+    // Grammars are checked out per extension, as extensions sharing one are processed concurrently.
+    let dest = grammar_id(&extension, &name);
+    validate_name(&dest)?;
 
-    let src = prefetch_git_repo(&repo, &rev, false).await?;
-    fs::remove_dir_all(&tmp_repo).await?;
+    let repo = grammar.repository.clone();
+    let checkout =
+        checkout_git_repo(cache, &repo, &grammar.rev, &checkout_dir("grammars", &dest)).await?;
+    let tmp_repo = &checkout.dir;
+
+    let src = prefetch_source(
+        cache,
+        &repo,
+        &checkout.resolved.commit,
+        &FetchOptions::default(),
+    )
+    .await?;
 
     let grammar_root = grammar
         .path
         .clone()
         .map(|s| s.trim_start_matches("./").to_owned());
 
+    let grammar_dir = grammar_root
+        .as_ref()
+        .map_or_else(|| tmp_repo.clone(), |root| tmp_repo.join(root));
+
+    let sources = inspect_grammar(&name, tmp_repo, &grammar_dir).await?;
+
+    if sources.needs_generate {
+        tracing::warn!(
+            cli = sources.cli_version,
+            "Missing src/parser.c, grammar needs generating"
+        );
+    } else if !sources.has_parser {
+        tracing::warn!("Missing src/parser.c and grammar definition");
+    }
+
+    let expected = format!("tree_sitter_{name}");
+    if let Some(symbol) = sources
+        .symbol
+        .as_ref()
+        .filter(|symbol| **symbol != expected)
+    {
+        tracing::warn!(
+            symbol = symbol,
+            expected = expected,
+            "Parser defines a different symbol"
+        );
+    }
+
+    let id = shared_grammar_id(&name, &src.url, &src.rev, grammar_root.as_deref());
+
     Ok(Some(Grammar {
         id,
         name: name.clone(),
-        version: rev,
+        version: checkout.resolved.commit.clone(),
+        reference: checkout.resolved.reference.clone(),
         src,
         grammar_root,
+        has_parser: Some(sources.has_parser),
+        symbol: sources.symbol,
+        scanner: sources.scanner,
+        needs_generate: sources.needs_generate,
+        cli_version: sources.cli_version,
+        abi_version: sources.abi_version,
+        metadata: sources.metadata,
     }))
 }
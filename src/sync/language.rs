@@ -0,0 +1,65 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use smol::{fs, stream::StreamExt};
+
+use crate::manifest::LanguageConfig;
+
+/// Maps each grammar used by the extension's languages to the names of those languages.
+///
+/// Languages are read from the directories listed in the manifest, or every directory under `languages/`.
+pub async fn read_languages(
+    extension_dir: &Path,
+    languages: &[PathBuf],
+) -> anyhow::Result<BTreeMap<String, Vec<String>>> {
+    let dirs = if languages.is_empty() {
+        let root = extension_dir.join("languages");
+        let mut dirs = vec![];
+        if root.exists() {
+            let mut entries = fs::read_dir(&root).await?;
+            while let Some(entry) = entries.try_next().await? {
+                if entry.file_type().await?.is_dir() {
+                    dirs.push(entry.path());
+                }
+            }
+        }
+
+        dirs.sort();
+        dirs
+    } else {
+        languages
+            .iter()
+            .map(|dir| extension_dir.join(dir))
+            .collect()
+    };
+
+    let mut grammars: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for dir in dirs {
+        let config = dir.join("config.toml");
+        if !config.exists() {
+            tracing::warn!(dir = %dir.display(), "Missing language config.toml");
+            continue;
+        }
+
+        let content = fs::read_to_string(&config).await?;
+        let config: LanguageConfig = match toml::from_str(&content) {
+            Ok(config) => config,
+            Err(err) => {
+                tracing::warn!(dir = %dir.display(), err = %err, "Failed to parse language config.toml");
+                continue;
+            }
+        };
+
+        if let Some(grammar) = config.grammar {
+            grammars.entry(grammar).or_default().push(config.name);
+        }
+    }
+
+    for languages in grammars.values_mut() {
+        languages.sort();
+    }
+
+    Ok(grammars)
+}
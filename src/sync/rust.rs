@@ -1,8 +1,7 @@
 use std::{
-    collections::BTreeMap,
-    env::temp_dir,
+    collections::{BTreeMap, BTreeSet},
     num::NonZero,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
     sync::Arc,
 };
 
@@ -12,8 +11,9 @@ use serde_json::Value;
 use smol::{fs, lock::Semaphore, process::Command, stream::StreamExt};
 use tracing::Instrument;
 
-use super::prefetch_git_repo;
+use super::{FetchOptions, checkout_dir, prefetch_git_repo};
 use crate::{
+    generated::{GeneratedDir, write_if_changed},
     output::{CargoLock, ExtensionKind},
     registry::RegistryExtension,
 };
@@ -27,9 +27,10 @@ pub async fn process_rust_extension(
     extension: &RegistryExtension,
     dir: &Path,
     name: &str,
+    output: &GeneratedDir,
 ) -> anyhow::Result<(ExtensionKind, Option<String>)> {
     let workspace = find_cargo_workspace(dir, extension.path.as_deref()).await?;
-    let has_stored_lockfile = process_cargo_lockfile(&workspace, dir, name).await?;
+    let has_stored_lockfile = process_cargo_lockfile(&workspace, dir, name, output).await?;
 
     let kind = calculate_rust_extension_kind(name, &workspace, has_stored_lockfile).await?;
     let root = calculate_rust_extension_root(&workspace, extension.path.as_deref());
@@ -52,32 +53,18 @@ async fn find_cargo_workspace(dir: &Path, path: Option<&str>) -> anyhow::Result<
         });
     };
 
-    let metadata = Command::new("cargo")
-        .args(["metadata", "--format-version=1", "--no-deps"])
-        .current_dir(dir)
-        .output()
-        .await?;
-
-    if metadata.status.success() {
-        let metadata_json: Value = serde_json::from_slice(&metadata.stdout)?;
-        if let Some(workspace_root) = metadata_json
-            .get("workspace_root")
-            .and_then(|value| value.as_str())
-            .map(Path::new)
-            && workspace_root != dir
-        {
-            let lockfile = workspace_root.join("Cargo.lock");
-            tracing::info!(
-                lockfile = ?lockfile,
-                root = ".",
-                "Using workspace lockfile"
-            );
+    if let Some(workspace_root) = find_workspace_root(dir).await? {
+        let lockfile = workspace_root.join("Cargo.lock");
+        tracing::info!(
+            lockfile = ?lockfile,
+            root = ".",
+            "Using workspace lockfile"
+        );
 
-            return Ok(CargoWorkspace {
-                lockfile,
-                root: None,
-            });
-        }
+        return Ok(CargoWorkspace {
+            lockfile,
+            root: None,
+        });
     }
 
     let lockfile = dir.join("Cargo.lock");
@@ -93,21 +80,103 @@ async fn find_cargo_workspace(dir: &Path, path: Option<&str>) -> anyhow::Result<
     })
 }
 
+/// Finds the root of the Cargo workspace the crate at `dir` is a member of, unless it's the crate itself.
+pub async fn find_workspace_root(dir: &Path) -> anyhow::Result<Option<PathBuf>> {
+    let metadata = Command::new("cargo")
+        .kill_on_drop(true)
+        .args(["metadata", "--format-version=1", "--no-deps"])
+        .current_dir(dir)
+        .output()
+        .await?;
+
+    if !metadata.status.success() {
+        return Ok(None);
+    }
+
+    let metadata_json: Value = serde_json::from_slice(&metadata.stdout)?;
+    Ok(metadata_json
+        .get("workspace_root")
+        .and_then(|value| value.as_str())
+        .map(PathBuf::from)
+        .filter(|workspace_root| workspace_root != dir))
+}
+
+/// Finds the directories of the `path` dependencies of the crate at `path` within the repository at `dir`, following theirs too.
+pub async fn find_path_dependencies(dir: &Path, path: &str) -> anyhow::Result<BTreeSet<String>> {
+    let mut dependencies = BTreeSet::new();
+    let mut pending = vec![path.to_owned()];
+    while let Some(crate_path) = pending.pop() {
+        let manifest_path = dir.join(&crate_path).join("Cargo.toml");
+        if !manifest_path.exists() {
+            continue;
+        }
+
+        let manifest: toml::Table = toml::from_str(&fs::read_to_string(&manifest_path).await?)?;
+        for dependency in manifest_path_dependencies(&manifest) {
+            let Some(dependency) = join_repository_path(&crate_path, dependency) else {
+                tracing::warn!(dependency, "Path dependency outside the repository");
+                continue;
+            };
+
+            if dependency != path && dependencies.insert(dependency.clone()) {
+                pending.push(dependency);
+            }
+        }
+    }
+
+    Ok(dependencies)
+}
+
+/// Lists the `path` of every dependency in `manifest`, including platform specific ones.
+fn manifest_path_dependencies(manifest: &toml::Table) -> Vec<&str> {
+    let targets = manifest
+        .get("target")
+        .and_then(|target| target.as_table())
+        .into_iter()
+        .flat_map(|targets| targets.values().filter_map(|target| target.as_table()));
+
+    std::iter::once(manifest)
+        .chain(targets)
+        .flat_map(|table| {
+            ["dependencies", "dev-dependencies", "build-dependencies"]
+                .into_iter()
+                .filter_map(|section| table.get(section).and_then(|section| section.as_table()))
+        })
+        .flat_map(|section| section.values())
+        .filter_map(|dependency| dependency.get("path").and_then(|path| path.as_str()))
+        .collect()
+}
+
+/// Resolves `relative` against `base` within a repository, unless it leaves it.
+fn join_repository_path(base: &str, relative: &str) -> Option<String> {
+    let joined = Path::new(base).join(relative);
+    let mut components = Vec::new();
+    for component in joined.components() {
+        match component {
+            Component::Normal(component) => components.push(component.to_str()?),
+            Component::ParentDir => {
+                components.pop()?;
+            }
+            Component::CurDir => {}
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(components.join("/"))
+}
+
 async fn process_cargo_lockfile(
     workspace: &CargoWorkspace,
     dir: &Path,
     name: &str,
+    output: &GeneratedDir,
 ) -> anyhow::Result<bool> {
-    let generated = Path::new("generated/extensions");
-    if !generated.exists() {
-        fs::create_dir_all(generated).await?;
-    }
-
-    let stored_lockfile = generated.join(format!("{name}.lock"));
+    let stored_lockfile = output.lockfile(name);
     if !workspace.lockfile.exists() {
         tracing::info!("Generating Cargo.lock");
 
         let generate = Command::new("cargo")
+            .kill_on_drop(true)
             .args(["generate-lockfile"])
             .current_dir(dir)
             .output()
@@ -118,7 +187,8 @@ async fn process_cargo_lockfile(
             return Err(anyhow::anyhow!("Failed to generate Cargo.lock"));
         }
 
-        fs::copy(&workspace.lockfile, &stored_lockfile).await?;
+        let lockfile = fs::read(&workspace.lockfile).await?;
+        write_if_changed(&stored_lockfile, &lockfile).await?;
         return Ok(true);
     }
 
@@ -171,10 +241,13 @@ async fn calculate_rust_extension_kind(
 }
 
 async fn generate_cargo_hash(name: &str, lockfile: &Path) -> anyhow::Result<String> {
-    let tmp_vendor = temp_dir().join(format!("{name}_vendor"));
+    let tmp_vendor = checkout_dir("vendor", name);
     if tmp_vendor.exists() {
         fs::remove_dir_all(&tmp_vendor).await?;
     }
+    if let Some(parent) = tmp_vendor.parent() {
+        fs::create_dir_all(parent).await?;
+    }
 
     tracing::info!(
         lockfile = ?lockfile,
@@ -183,6 +256,7 @@ async fn generate_cargo_hash(name: &str, lockfile: &Path) -> anyhow::Result<Stri
     );
 
     let vendor = Command::new("fetch-cargo-vendor-util")
+        .kill_on_drop(true)
         .args([
             "create-vendor-staging",
             &lockfile.to_string_lossy(),
@@ -196,6 +270,7 @@ async fn generate_cargo_hash(name: &str, lockfile: &Path) -> anyhow::Result<Stri
     }
 
     let hash = Command::new("nix-hash")
+        .kill_on_drop(true)
         .args(["--type", "sha256", "--sri", &tmp_vendor.to_string_lossy()])
         .output()
         .await?;
@@ -271,7 +346,14 @@ async fn calculate_cargo_output_hashes(
 
         let future = async move {
             let _permit = semaphore.acquire().await;
-            let src = prefetch_git_repo(&url, &rev, true).instrument(span).await?;
+            let options = FetchOptions {
+                submodules: true,
+                ..FetchOptions::default()
+            };
+
+            let src = prefetch_git_repo(&url, &rev, &options)
+                .instrument(span)
+                .await?;
             anyhow::Ok((key, src.hash))
         };
 
@@ -301,3 +383,45 @@ async fn calculate_cargo_output_hashes(
 
     Ok(output)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn joins_repository_paths() {
+        assert_eq!(
+            join_repository_path("extensions/one", "../shared").as_deref(),
+            Some("extensions/shared")
+        );
+        assert_eq!(
+            join_repository_path("extensions/one", "./../../crates/api/").as_deref(),
+            Some("crates/api")
+        );
+        assert_eq!(join_repository_path("one", "..").as_deref(), Some(""));
+        assert_eq!(join_repository_path("one", "../.."), None);
+        assert_eq!(join_repository_path("one", "/usr/src"), None);
+    }
+
+    #[test]
+    fn lists_manifest_path_dependencies() {
+        let manifest: toml::Table = toml::from_str(
+            r#"
+            [dependencies]
+            api = "0.1"
+            shared = { path = "../shared" }
+
+            [build-dependencies]
+            build = { path = "../build" }
+
+            [target.'cfg(unix)'.dev-dependencies]
+            unix = { path = "../unix" }
+            "#,
+        )
+        .unwrap();
+
+        let mut dependencies = manifest_path_dependencies(&manifest);
+        dependencies.sort_unstable();
+        assert_eq!(dependencies, ["../build", "../shared", "../unix"]);
+    }
+}
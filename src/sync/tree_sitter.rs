@@ -0,0 +1,220 @@
+//! Build details read from a tree-sitter grammar checkout.
+
+use std::path::Path;
+
+use serde_json::Value;
+use smol::fs;
+
+use crate::output::{GrammarMetadata, Scanner, ScannerLanguage};
+
+const PARSER: &str = "src/parser.c";
+const CONFIG: &str = "tree-sitter.json";
+
+/// Grammar definitions `tree-sitter generate` can produce a parser from.
+const DEFINITIONS: [&str; 2] = ["grammar.js", "src/grammar.json"];
+
+/// External scanners, in the order tree-sitter looks for them.
+const SCANNERS: [(&str, ScannerLanguage); 3] = [
+    ("src/scanner.c", ScannerLanguage::C),
+    ("src/scanner.cc", ScannerLanguage::Cpp),
+    ("src/scanner.cpp", ScannerLanguage::Cpp),
+];
+
+pub struct GrammarSources {
+    pub has_parser: bool,
+    pub symbol: Option<String>,
+    pub scanner: Option<Scanner>,
+    /// Whether the parser is missing but can be generated.
+    pub needs_generate: bool,
+    /// The `tree-sitter-cli` version requirement from `package.json`, for grammars that need generating.
+    pub cli_version: Option<String>,
+    /// The `LANGUAGE_VERSION` from `parser.c`.
+    pub abi_version: Option<u32>,
+    pub metadata: Option<GrammarMetadata>,
+}
+
+/// Inspects the grammar `name` at `dir`, the grammar root within the checkout at `repo`.
+pub async fn inspect_grammar(
+    name: &str,
+    repo: &Path,
+    dir: &Path,
+) -> anyhow::Result<GrammarSources> {
+    let parser = dir.join(PARSER);
+    let has_parser = parser.exists();
+
+    let (symbol, abi_version) = if has_parser {
+        let content = fs::read(&parser).await?;
+        let content = String::from_utf8_lossy(&content);
+        (
+            find_language_symbol(&content),
+            find_language_version(&content),
+        )
+    } else {
+        (None, None)
+    };
+
+    let scanner = SCANNERS
+        .iter()
+        .find(|(file, _)| dir.join(file).exists())
+        .map(|(file, language)| Scanner {
+            file: (*file).to_owned(),
+            language: *language,
+        });
+
+    let needs_generate = !has_parser && DEFINITIONS.iter().any(|file| dir.join(file).exists());
+    let cli_version = if needs_generate {
+        find_cli_version(dir).await?
+    } else {
+        None
+    };
+
+    // Multi-grammar repositories keep `tree-sitter.json` at their root.
+    let metadata = match [dir, repo]
+        .iter()
+        .map(|dir| dir.join(CONFIG))
+        .find(|path| path.exists())
+    {
+        Some(config) => read_metadata(name, &config).await?,
+        None => None,
+    };
+
+    Ok(GrammarSources {
+        has_parser,
+        symbol,
+        scanner,
+        needs_generate,
+        cli_version,
+        abi_version,
+        metadata,
+    })
+}
+
+/// Finds the ABI version `parser.c` was generated for, like `#define LANGUAGE_VERSION 14`.
+fn find_language_version(parser: &str) -> Option<u32> {
+    parser.lines().find_map(|line| {
+        line.trim()
+            .strip_prefix("#define LANGUAGE_VERSION")?
+            .trim()
+            .parse()
+            .ok()
+    })
+}
+
+/// Reads the metadata of `name` from `tree-sitter.json`.
+///
+/// Grammar details come from its entry in `grammars`, or the only entry if none match.
+async fn read_metadata(name: &str, config: &Path) -> anyhow::Result<Option<GrammarMetadata>> {
+    let content = fs::read_to_string(config).await?;
+    let Ok(config) = serde_json::from_str::<Value>(&content) else {
+        tracing::warn!("Failed to parse tree-sitter.json");
+        return Ok(None);
+    };
+
+    let grammars = config["grammars"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let grammar = grammars
+        .iter()
+        .find(|grammar| grammar["name"].as_str() == Some(name))
+        .or(match grammars {
+            [grammar] => Some(grammar),
+            _ => None,
+        });
+
+    let string = |value: &Value| value.as_str().map(str::to_owned);
+    let metadata = &config["metadata"];
+
+    Ok(Some(GrammarMetadata {
+        version: string(&metadata["version"]),
+        license: string(&metadata["license"]),
+        description: string(&metadata["description"]),
+        scope: grammar.and_then(|grammar| string(&grammar["scope"])),
+    }))
+}
+
+/// Reads the `tree-sitter-cli` dependency from `package.json`, like `^0.22.6`.
+async fn find_cli_version(dir: &Path) -> anyhow::Result<Option<String>> {
+    let package = dir.join("package.json");
+    if !package.exists() {
+        return Ok(None);
+    }
+
+    let content = fs::read_to_string(&package).await?;
+    let Ok(package) = serde_json::from_str::<Value>(&content) else {
+        tracing::warn!("Failed to parse package.json");
+        return Ok(None);
+    };
+
+    Ok(["devDependencies", "dependencies"]
+        .iter()
+        .find_map(|key| package[key]["tree-sitter-cli"].as_str())
+        .map(str::to_owned))
+}
+
+/// Finds the language function defined by `parser.c`, like `const TSLanguage *tree_sitter_nix(void)`.
+///
+/// The definition comes last, so the file is searched from the end.
+fn find_language_symbol(parser: &str) -> Option<String> {
+    parser
+        .lines()
+        .rev()
+        .filter(|line| line.contains("TSLanguage"))
+        .find_map(|line| {
+            let start = line.find("tree_sitter_")?;
+            let rest = &line[start..];
+            let end = rest
+                .find(|char: char| !(char.is_ascii_alphanumeric() || char == '_'))
+                .unwrap_or(rest.len());
+
+            rest[end..]
+                .trim_start()
+                .starts_with('(')
+                .then(|| rest[..end].to_owned())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_language_symbol() {
+        let parser = r"
+static const TSLanguage *language_ts_nix = NULL;
+
+#ifdef __cplusplus
+extern C {
+#endif
+
+TS_PUBLIC const TSLanguage *tree_sitter_nix(void) {
+  static const TSLanguage language = {
+";
+        assert_eq!(
+            find_language_symbol(parser).as_deref(),
+            Some("tree_sitter_nix")
+        );
+    }
+
+    #[test]
+    fn finds_last_language_definition() {
+        let parser = r"
+extern const TSLanguage *tree_sitter_markdown_inline (void);
+const TSLanguage *tree_sitter_markdown(void) {
+";
+        assert_eq!(
+            find_language_symbol(parser).as_deref(),
+            Some("tree_sitter_markdown")
+        );
+    }
+
+    #[test]
+    fn ignores_non_functions() {
+        assert_eq!(
+            find_language_symbol("const TSLanguage *tree_sitter_nix_language;"),
+            None
+        );
+        assert_eq!(find_language_symbol("void tree_sitter_nix(void);"), None);
+        assert_eq!(find_language_symbol(""), None);
+    }
+}
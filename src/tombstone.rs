@@ -0,0 +1,30 @@
+//! Records of extensions that were removed from their registry.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use smol::fs;
+
+use crate::output::{Extension, Grammar};
+
+/// The last known state of a removed extension, kept so it can still be built.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub name: String,
+    /// When the removal was noticed.
+    pub removed: String,
+    /// Last synced version of the extension.
+    pub extension: Extension,
+    pub grammars: Vec<Grammar>,
+}
+
+impl Tombstone {
+    pub async fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let content = fs::read_to_string(path).await?;
+        Ok(Some(serde_json::from_str(&content)?))
+    }
+}
@@ -0,0 +1,215 @@
+//! Checks for registry and manifest data that ends up in file names or command arguments.
+
+use crate::{
+    manifest::GrammarManifestEntry, overrides::validate_source, registry::RegistryExtension,
+};
+
+const MAX_LENGTH: usize = 128;
+
+/// Checks a name that is used as a file name, like an extension name or grammar id.
+///
+/// Only ASCII letters, digits, `-` and `_` are allowed, so it can't point outside its directory.
+pub fn validate_name(value: &str) -> anyhow::Result<()> {
+    if value.is_empty() || value.len() > MAX_LENGTH {
+        anyhow::bail!("Invalid name: '{value}' (must be 1 to {MAX_LENGTH} characters)");
+    }
+
+    if !value
+        .chars()
+        .all(|char| char.is_ascii_alphanumeric() || char == '-' || char == '_')
+    {
+        anyhow::bail!(
+            "Invalid name: '{value}' (only ASCII letters, digits, '-' and '_' are allowed)"
+        );
+    }
+
+    if value.starts_with('-') {
+        anyhow::bail!("Invalid name: '{value}' (can't start with '-')");
+    }
+
+    Ok(())
+}
+
+/// Checks an extension name, which is also the first half of its grammar ids.
+///
+/// Ruling out `__` and a trailing `_` keeps grammar ids unambiguous, see [`grammar_id`](crate::output::grammar_id).
+pub fn validate_extension_name(value: &str) -> anyhow::Result<()> {
+    validate_name(value)?;
+
+    if value.contains("__") || value.ends_with('_') {
+        anyhow::bail!("Invalid extension name: '{value}' (can't contain '__' or end with '_')");
+    }
+
+    Ok(())
+}
+
+/// Checks a grammar name, which is the second half of its grammar id.
+pub fn validate_grammar_name(value: &str) -> anyhow::Result<()> {
+    validate_name(value)?;
+
+    if value.contains("__") || value.starts_with('_') {
+        anyhow::bail!("Invalid grammar name: '{value}' (can't contain '__' or start with '_')");
+    }
+
+    Ok(())
+}
+
+/// Checks a version, which is used as a file name in version histories.
+pub fn validate_version(value: &str) -> anyhow::Result<()> {
+    if value.is_empty() || value.len() > MAX_LENGTH {
+        anyhow::bail!("Invalid version: '{value}' (must be 1 to {MAX_LENGTH} characters)");
+    }
+
+    if !value
+        .chars()
+        .all(|char| char.is_ascii_alphanumeric() || matches!(char, '.' | '_' | '+' | '-'))
+    {
+        anyhow::bail!(
+            "Invalid version: '{value}' (only ASCII letters, digits, '.', '_', '+' and '-' are allowed)"
+        );
+    }
+
+    if value.starts_with(['.', '-']) {
+        anyhow::bail!("Invalid version: '{value}' (can't start with '.' or '-')");
+    }
+
+    Ok(())
+}
+
+/// Checks everything about a registry entry that is used before its manifest is read.
+pub fn validate_registry_extension(extension: &RegistryExtension) -> anyhow::Result<()> {
+    validate_extension_name(&extension.name)?;
+    validate_git_source(
+        &extension.repository,
+        &extension.rev,
+        extension.path.as_deref(),
+    )
+}
+
+/// Checks the source of a grammar listed in an extension manifest.
+pub fn validate_grammar_entry(grammar: &GrammarManifestEntry) -> anyhow::Result<()> {
+    validate_git_source(&grammar.repository, &grammar.rev, grammar.path.as_deref())
+}
+
+/// Checks a repository, revision and path that end up in git arguments and checkout paths.
+fn validate_git_source(repository: &str, rev: &str, path: Option<&str>) -> anyhow::Result<()> {
+    let mut problems = validate_source(Some(rev), None, path);
+
+    // Any git remote is fine, as long as it can't be mistaken for an option.
+    if repository.is_empty()
+        || repository.starts_with('-')
+        || repository.contains(char::is_whitespace)
+    {
+        problems.push(format!(
+            "'repository' is not a valid git remote: '{repository}'"
+        ));
+    }
+
+    if !problems.is_empty() {
+        anyhow::bail!("Invalid source: {}", problems.join(", "));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extension(name: &str, repository: &str, rev: &str, path: Option<&str>) -> RegistryExtension {
+        RegistryExtension {
+            name: name.to_owned(),
+            version: "0.1.0".to_owned(),
+            repository: repository.to_owned(),
+            path: path.map(ToOwned::to_owned),
+            rev: rev.to_owned(),
+            registry: "official".to_owned(),
+            registry_rev: None,
+        }
+    }
+
+    #[test]
+    fn validates_names() {
+        assert!(validate_name("nix").is_ok());
+        assert!(validate_name("tree-sitter_nix2").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name(&"a".repeat(MAX_LENGTH + 1)).is_err());
+        assert!(validate_name("../nix").is_err());
+        assert!(validate_name("-nix").is_err());
+        assert!(validate_name("nix.json").is_err());
+    }
+
+    #[test]
+    fn keeps_grammar_ids_unambiguous() {
+        assert!(validate_extension_name("my_extension").is_ok());
+        assert!(validate_extension_name("my__extension").is_err());
+        assert!(validate_extension_name("extension_").is_err());
+
+        assert!(validate_grammar_name("c_sharp").is_ok());
+        assert!(validate_grammar_name("c__sharp").is_err());
+        assert!(validate_grammar_name("_sharp").is_err());
+    }
+
+    #[test]
+    fn validates_versions() {
+        assert!(validate_version("0.1.0").is_ok());
+        assert!(validate_version("1.0.0-beta+build_2").is_ok());
+        assert!(validate_version("").is_err());
+        assert!(validate_version("../1.0.0").is_err());
+        assert!(validate_version(".1").is_err());
+        assert!(validate_version("-1").is_err());
+        assert!(validate_version("1 0").is_err());
+    }
+
+    #[test]
+    fn validates_registry_extensions() {
+        let repository = "https://github.com/example/zed-nix";
+        let rev = "0123456789abcdef0123456789abcdef01234567";
+        assert!(validate_registry_extension(&extension("nix", repository, rev, None)).is_ok());
+        assert!(
+            validate_registry_extension(&extension("nix", repository, rev, Some("./nix"))).is_ok()
+        );
+
+        assert!(validate_registry_extension(&extension("nix__", repository, rev, None)).is_err());
+        assert!(
+            validate_registry_extension(&extension("nix", "--upload-pack=x", rev, None)).is_err()
+        );
+        assert!(validate_registry_extension(&extension("nix", repository, "--all", None)).is_err());
+        assert!(
+            validate_registry_extension(&extension("nix", repository, rev, Some("../nix")))
+                .is_err()
+        );
+        assert!(
+            validate_registry_extension(&extension("nix", repository, rev, Some("/nix"))).is_err()
+        );
+    }
+
+    #[test]
+    fn validates_grammar_entries() {
+        let entry = |source: &str| toml::from_str::<GrammarManifestEntry>(source).unwrap();
+
+        assert!(
+            validate_grammar_entry(&entry(
+                r#"repository = "https://github.com/example/tree-sitter-nix"
+                commit = "0123456789abcdef0123456789abcdef01234567""#
+            ))
+            .is_ok()
+        );
+        assert!(
+            validate_grammar_entry(&entry(
+                r#"repository = "https://github.com/example/tree-sitter-nix"
+                rev = "main"
+                path = "../.."
+                "#
+            ))
+            .is_err()
+        );
+        assert!(
+            validate_grammar_entry(&entry(
+                r#"repository = "-c core.sshCommand=x"
+                rev = "main""#
+            ))
+            .is_err()
+        );
+    }
+}
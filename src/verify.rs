@@ -0,0 +1,139 @@
+//! Checks generated data for references to entries that don't exist.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use serde_json::Value;
+use smol::{fs, stream::StreamExt};
+
+use crate::generated::GeneratedDir;
+
+/// Fails if any extension, grammar or alias refers to a missing source, grammar, extension or lockfile.
+///
+/// Sources that nothing refers to are only warned about.
+pub async fn verify(dir: &GeneratedDir) -> anyhow::Result<()> {
+    let sources = dir.load_sources().await?;
+
+    // Read as plain JSON, as loading skips files with a missing source.
+    let extensions = read_entries(&dir.extensions_dir()).await?;
+    let grammars = read_entries(&dir.grammars_dir()).await?;
+
+    let mut problems = vec![];
+    let mut used = BTreeSet::new();
+
+    let entries = extensions
+        .iter()
+        .map(|(name, value)| ("Extension", name, value))
+        .chain(grammars.iter().map(|(id, value)| ("Grammar", id, value)));
+
+    for (kind, name, value) in entries {
+        match &value["src"] {
+            Value::String(key) => {
+                used.insert(key.clone());
+                if !sources.contains_key(key) {
+                    problems.push(format!("{kind} '{name}' refers to missing source '{key}'"));
+                }
+            }
+            // Embedded by older versions.
+            Value::Object(_) => (),
+            _ => problems.push(format!("{kind} '{name}' has no source")),
+        }
+    }
+
+    for (name, extension) in &extensions {
+        for id in extension["grammars"].as_array().into_iter().flatten() {
+            let id = id.as_str().unwrap_or_default();
+            if !grammars.contains_key(id) {
+                problems.push(format!(
+                    "Extension '{name}' refers to missing grammar '{id}'"
+                ));
+            }
+        }
+
+        // Stored like `/generated/extensions/<name>.lock`, so resolved within `dir` whatever it's called.
+        if let Some(lockfile) = extension["cargoLock"]["lockFile"].as_str()
+            && !lockfile
+                .trim_start_matches('/')
+                .split_once('/')
+                .is_some_and(|(_, path)| dir.root().join(path).exists())
+        {
+            problems.push(format!(
+                "Extension '{name}' refers to missing lockfile '{lockfile}'"
+            ));
+        }
+    }
+
+    for (alias, id) in read_aliases(&dir.grammar_aliases()).await? {
+        if !grammars.contains_key(&id) {
+            problems.push(format!(
+                "Grammar alias '{alias}' refers to missing grammar '{id}'"
+            ));
+        }
+    }
+
+    for (old, new) in read_aliases(&dir.aliases()).await? {
+        if !extensions.contains_key(&new) {
+            problems.push(format!("Alias '{old}' refers to missing extension '{new}'"));
+        }
+    }
+
+    for key in sources.keys().filter(|key| !used.contains(*key)) {
+        tracing::warn!(key = key, "Unused source");
+    }
+
+    if !problems.is_empty() {
+        for problem in &problems {
+            tracing::error!("{problem}");
+        }
+
+        anyhow::bail!("Found {} dangling reference(s)", problems.len());
+    }
+
+    tracing::info!(
+        extensions = extensions.len(),
+        grammars = grammars.len(),
+        sources = sources.len(),
+        "Generated data is consistent"
+    );
+
+    Ok(())
+}
+
+/// Reads every JSON file in `dir`, keyed by file name.
+async fn read_entries(dir: &Path) -> anyhow::Result<BTreeMap<String, Value>> {
+    let mut entries = BTreeMap::new();
+    if !dir.exists() {
+        return Ok(entries);
+    }
+
+    let mut files = fs::read_dir(dir).await?;
+    while let Some(file) = files.try_next().await? {
+        let path = file.path();
+        let Some(name) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".json"))
+        else {
+            continue;
+        };
+
+        let content = fs::read_to_string(&path).await?;
+        let value = serde_json::from_str(&content)
+            .map_err(|err| anyhow::anyhow!("Failed to parse {}: {err}", path.display()))?;
+
+        entries.insert(name.to_owned(), value);
+    }
+
+    Ok(entries)
+}
+
+async fn read_aliases(path: &Path) -> anyhow::Result<BTreeMap<String, String>> {
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+
+    let content = fs::read_to_string(path).await?;
+    Ok(serde_json::from_str(&content)?)
+}